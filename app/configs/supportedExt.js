@@ -1 +1 @@
-export default ['jpeg', 'jpg', 'png'];
+export default ['jpeg', 'jpg', 'png', 'gif', 'webp', 'svg'];
@@ -0,0 +1,241 @@
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::types::{FileRecord, SizeOutcome, VariantFormat};
+
+const ASSETS_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct AssetManifest {
+    version: u32,
+    generated: u64,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Serialize)]
+struct Asset {
+    source: String,
+    width: u32,
+    height: u32,
+    files: Vec<AssetFile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    picture: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AssetFile {
+    path: String,
+    mime: &'static str,
+    width: u32,
+    height: u32,
+    bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    descriptor: Option<String>,
+}
+
+pub fn write_asset_manifest(
+    files: &[FileRecord],
+    path: &Path,
+    with_snippets: bool,
+) -> Result<(), String> {
+    let base = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let assets = files
+        .iter()
+        .filter(|f| f.stats.completed)
+        .filter_map(|f| build_asset(f, base, with_snippets))
+        .collect();
+
+    let manifest = AssetManifest {
+        version: ASSETS_VERSION,
+        generated: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        assets,
+    };
+
+    let data = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    fs::write(path, data).map_err(|e| format!("Failed to write asset manifest: {}", e))
+}
+
+fn build_asset(record: &FileRecord, base: &Path, with_snippets: bool) -> Option<Asset> {
+    let s = &record.stats;
+    let dest = PathBuf::from(&record.destination);
+
+    // Without optimizing the original in copy mode, `dest` was never written.
+    let original = if dest.is_file() {
+        dest.clone()
+    } else {
+        PathBuf::from(&record.source)
+    };
+    // The image crate can't read SVGs; their size comes from the parsed tree.
+    let (width, height) = match image::image_dimensions(&original) {
+        Ok(dimensions) => dimensions,
+        Err(_) if s.width > 0 => (s.width, s.height),
+        Err(_) => return None,
+    };
+
+    let mut files = vec![asset_file(base, &original, width, height, None)?];
+
+    // JPEG XL has no byte budget, so it always keeps the decoded size.
+    for (ext, outcome, dimensions) in [
+        ("jxl", s.jxl_outcome, None),
+        (
+            VariantFormat::Avif.extension(),
+            s.avif_outcome,
+            s.avif_dimensions,
+        ),
+        (
+            VariantFormat::Webp.extension(),
+            s.webp_outcome,
+            s.webp_dimensions,
+        ),
+    ] {
+        if outcome != SizeOutcome::Optimized {
+            continue;
+        }
+        let path = dest.with_extension(ext);
+        let (width, height) = dimensions.unwrap_or((s.width, s.height));
+        files.extend(asset_file(base, &path, width, height, None));
+    }
+
+    for v in s
+        .variants
+        .iter()
+        .filter(|v| v.outcome == SizeOutcome::Optimized)
+    {
+        files.extend(asset_file(
+            base,
+            Path::new(&v.path),
+            v.width,
+            v.height,
+            Some(v.descriptor.clone()),
+        ));
+    }
+
+    // Any `<source>` would win over an SVG fallback, so SVGs get no snippet.
+    let picture =
+        (with_snippets && files[0].mime != "image/svg+xml").then(|| picture_snippet(&files));
+
+    Some(Asset {
+        source: record.source.clone(),
+        width,
+        height,
+        files,
+        picture,
+    })
+}
+
+fn asset_file(
+    base: &Path,
+    path: &Path,
+    width: u32,
+    height: u32,
+    descriptor: Option<String>,
+) -> Option<AssetFile> {
+    let bytes = fs::metadata(path).ok()?.len();
+    Some(AssetFile {
+        path: relative_url(base, path),
+        mime: mime_type(path),
+        width,
+        height,
+        bytes,
+        descriptor,
+    })
+}
+
+// The first file is the fallback `<img>`, every other format becomes a
+// `<source>`. Width descriptors win over densities since a srcset can't mix them.
+fn picture_snippet(files: &[AssetFile]) -> String {
+    let fallback = &files[0];
+    let uses_widths = files
+        .iter()
+        .any(|f| f.descriptor.as_deref().is_some_and(|d| d.ends_with('w')));
+
+    let mut out = String::from("<picture>\n");
+
+    for mime in ["image/jxl", "image/avif", "image/webp"] {
+        let (main, variants): (Vec<&AssetFile>, Vec<&AssetFile>) = files[1..]
+            .iter()
+            .filter(|f| f.mime == mime)
+            .partition(|f| f.descriptor.is_none());
+
+        let srcset: Vec<String> = if uses_widths {
+            variants
+                .iter()
+                .filter(|f| f.descriptor.as_deref().is_some_and(|d| d.ends_with('w')))
+                .chain(main.iter())
+                .map(|f| format!("{} {}w", f.path, f.width))
+                .collect()
+        } else if !variants.is_empty() {
+            variants
+                .iter()
+                .map(|f| format!("{} {}", f.path, f.descriptor.as_deref().unwrap_or("1x")))
+                .collect()
+        } else {
+            main.iter().map(|f| f.path.clone()).collect()
+        };
+        if srcset.is_empty() {
+            continue;
+        }
+
+        out.push_str(&format!(
+            "  <source type=\"{}\" srcset=\"{}\"{}>\n",
+            mime,
+            escape_attr(&srcset.join(", ")),
+            if uses_widths { " sizes=\"100vw\"" } else { "" }
+        ));
+    }
+
+    out.push_str(&format!(
+        "  <img src=\"{}\" width=\"{}\" height=\"{}\" alt=\"\" loading=\"lazy\" decoding=\"async\">\n",
+        escape_attr(&fallback.path),
+        fallback.width,
+        fallback.height
+    ));
+    out.push_str("</picture>");
+    out
+}
+
+fn relative_url(base: &Path, path: &Path) -> String {
+    let base = fs::canonicalize(base).unwrap_or_else(|_| base.to_path_buf());
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.strip_prefix(&base)
+        .unwrap_or(&path)
+        .to_string_lossy()
+        .replace('\\', "/")
+        .replace(' ', "%20")
+        .replace(',', "%2C")
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "jxl" => "image/jxl",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::types::{OptimizeConfig, UndoResult};
+
+const RUN_FILE: &str = "run.json";
+const ENTRIES_FILE: &str = "entries.jsonl";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RunInfo {
+    id: String,
+    created: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupEntry {
+    original: String,
+    backup: String,
+}
+
+pub struct RunBackup {
+    id: String,
+    dir: PathBuf,
+    counter: AtomicUsize,
+    entries: Mutex<File>,
+}
+
+impl RunBackup {
+    pub fn begin(config: &OptimizeConfig) -> Result<Self, String> {
+        let root = backup_root(config)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let id = format!("{:013}", now.as_millis());
+        let dir = root.join(&id);
+
+        let setup = || -> std::io::Result<File> {
+            fs::create_dir_all(&dir)?;
+            let info = RunInfo {
+                id: id.clone(),
+                created: now.as_secs(),
+            };
+            fs::write(dir.join(RUN_FILE), serde_json::to_vec_pretty(&info)?)?;
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(ENTRIES_FILE))
+        };
+        let entries = setup().map_err(|e| format!("Failed to prepare backup folder: {}", e))?;
+
+        Ok(Self {
+            id,
+            dir,
+            counter: AtomicUsize::new(0),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counter.load(Ordering::Relaxed) == 0
+    }
+
+    pub fn store(&self, original: &Path) -> std::io::Result<()> {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let name = format!(
+            "{}-{}.bak",
+            n,
+            original.file_name().unwrap_or_default().to_string_lossy()
+        );
+        fs::copy(original, self.dir.join(&name))?;
+
+        let entry = BackupEntry {
+            original: original.to_string_lossy().to_string(),
+            backup: name,
+        };
+        let line = serde_json::to_string(&entry)?;
+
+        let mut file = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, "{}", line)?;
+        file.sync_data()
+    }
+
+    pub fn discard(self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn backup_root(config: &OptimizeConfig) -> Result<PathBuf, String> {
+    config
+        .backup_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .ok_or_else(|| "Backup folder is not configured.".to_string())
+}
+
+pub fn undo_run(root: &Path, run_id: Option<&str>) -> Result<UndoResult, String> {
+    let id = match run_id {
+        Some(id) => id.to_string(),
+        None => list_runs(root)
+            .pop()
+            .ok_or_else(|| "There is no run to undo.".to_string())?,
+    };
+
+    let dir = root.join(&id);
+    let file = File::open(dir.join(ENTRIES_FILE)).map_err(|_| format!("Unknown run '{}'.", id))?;
+
+    let mut restored = 0;
+    let mut failed = Vec::new();
+
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let Ok(entry) = serde_json::from_str::<BackupEntry>(&line) else {
+            continue;
+        };
+        match restore_file(&dir.join(&entry.backup), Path::new(&entry.original)) {
+            Ok(()) => restored += 1,
+            Err(e) => failed.push(format!("{}: {}", entry.original, e)),
+        }
+    }
+
+    if failed.is_empty() {
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    Ok(UndoResult {
+        run_id: id,
+        restored,
+        failed,
+    })
+}
+
+// `keep` is the run that just finished: it is never evicted, even when it
+// alone is over `max_size_mb`, so it can always be undone.
+pub fn prune(root: &Path, keep: &str, max_age_days: u64, max_size_mb: u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut runs: Vec<(PathBuf, u64, u64)> = list_runs(root)
+        .into_iter()
+        .filter(|id| id != keep)
+        .map(|id| {
+            let dir = root.join(id);
+            let created = read_created(&dir).unwrap_or(0);
+            let size = dir_size(&dir);
+            (dir, created, size)
+        })
+        .collect();
+
+    if max_age_days > 0 {
+        let max_age = max_age_days * 24 * 60 * 60;
+        runs.retain(|(dir, created, _)| {
+            let expired = now.saturating_sub(*created) > max_age;
+            if expired {
+                let _ = fs::remove_dir_all(dir);
+            }
+            !expired
+        });
+    }
+
+    if max_size_mb > 0 {
+        let limit = max_size_mb * 1024 * 1024;
+        let mut total: u64 = dir_size(&root.join(keep));
+        total += runs.iter().map(|(_, _, size)| size).sum::<u64>();
+        for (dir, _, size) in &runs {
+            if total <= limit {
+                break;
+            }
+            if fs::remove_dir_all(dir).is_ok() {
+                total -= size;
+            }
+        }
+    }
+}
+
+fn restore_file(backup: &Path, original: &Path) -> std::io::Result<()> {
+    let tmp = original.with_extension("restoring.tmp");
+    fs::copy(backup, &tmp)?;
+    fs::rename(&tmp, original).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+fn list_runs(root: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+
+    let mut runs: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().join(RUN_FILE).is_file())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    runs.sort();
+    runs
+}
+
+fn read_created(dir: &Path) -> Option<u64> {
+    let data = fs::read(dir.join(RUN_FILE)).ok()?;
+    serde_json::from_slice::<RunInfo>(&data)
+        .ok()
+        .map(|i| i.created)
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.metadata().ok())
+                .map(|m| m.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_run(root: &Path, id: &str, created: u64, size: usize) {
+        let dir = root.join(id);
+        fs::create_dir_all(&dir).unwrap();
+        let info = RunInfo {
+            id: id.to_string(),
+            created,
+        };
+        fs::write(dir.join(RUN_FILE), serde_json::to_vec(&info).unwrap()).unwrap();
+        fs::write(dir.join("0-photo.jpg.bak"), vec![0; size]).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_stored_originals() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join(".originals");
+        let original = dir.path().join("photo.jpg");
+        fs::write(&original, b"original").unwrap();
+
+        let config = OptimizeConfig {
+            backup_dir: Some(root.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let backup = RunBackup::begin(&config).unwrap();
+        backup.store(&original).unwrap();
+        fs::write(&original, b"optimized").unwrap();
+
+        let res = undo_run(&root, None).unwrap();
+        assert_eq!(res.run_id, backup.id());
+        assert_eq!(res.restored, 1);
+        assert!(res.failed.is_empty());
+        assert_eq!(fs::read(&original).unwrap(), b"original");
+        assert!(!root.join(backup.id()).exists());
+    }
+
+    #[test]
+    fn prune_keeps_the_current_run_past_both_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        fake_run(root, "0000000000001", 0, 16);
+        fake_run(root, "0000000000002", now, 1024 * 1024);
+        fake_run(root, "0000000000003", 0, 2 * 1024 * 1024);
+
+        prune(root, "0000000000003", 1, 1);
+
+        assert_eq!(list_runs(root), ["0000000000003"]);
+    }
+}
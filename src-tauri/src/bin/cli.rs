@@ -0,0 +1,538 @@
+use std::env;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use app_lib::backup::{backup_root, undo_run};
+use app_lib::optimizer::perform_optimization;
+use app_lib::report::write_report;
+use app_lib::reporter::{ProgressEvent, ProgressReporter};
+use app_lib::types::{
+    AvifColorModel, ChromaSubsampling, FileTask, FinalResult, IccPolicy, MetadataPolicy,
+    OptimizeConfig, ResizeFilter, ResizeFit, SvgRasterFormat, WebpLossless,
+};
+use humansize::{format_size, DECIMAL};
+
+const USAGE: &str = "\
+Usage: images-optimizer-cli [OPTIONS] <PATH>...
+       images-optimizer-cli --undo [--undo-run <ID>] [--backup-dir <DIR>] [<PATH>]
+
+Optimizes every JPEG/PNG/GIF/WebP/SVG found in the given files and folders.
+Animated GIFs become animated WebP; AVIF and variants are skipped for them.
+WebP sources are re-encoded with the WebP settings. AVIF sources are not supported.
+SVGs are minified and can be rendered to PNG/WebP with --svg-raster.
+
+Options:
+      --jpg-q <N>             JPEG quality, 1-100 [default: 80]
+      --jpg-lossless          Rewrite JPEGs from their DCT coefficients only (pixels unchanged;
+                              ignores --jpg-q, resizing, quality targets and budgets)
+      --jpg-subsampling <S>   JPEG chroma subsampling, 444, 422 or 420 [default: 420]
+      --jpg-baseline          Write baseline JPEGs instead of progressive ones
+      --jpg-no-trellis        Disable trellis quantization (faster, larger files)
+      --jpg-smoothing <N>     JPEG input smoothing, 0 (off) to 100 [default: 0]
+      --png-min <N>           Minimum PNG quantization quality [default: 65]
+      --png-max <N>           Maximum PNG quantization quality [default: 80]
+      --webp                  Also generate a .webp next to each output
+      --avif                  Also generate an .avif next to each output
+      --webp-q <N>            WebP quality, or effort when lossless, 0-100 [default: 75]
+      --webp-lossless <MODE>  auto, always or never [default: auto]
+      --webp-near-lossless <N>
+                              Near-lossless preprocessing level, 100 is off [default: 100]
+      --webp-alpha-q <N>      WebP alpha channel quality, 0-100 [default: 100]
+      --webp-method <N>       WebP effort, 0 (fast) to 6 (small) [default: 4]
+      --avif-q <N>            AVIF quality, 1-100 [default: 65]
+      --avif-alpha-q <N>      AVIF alpha channel quality, 1-100 [default: 70]
+      --avif-speed <N>        AVIF speed, 1 (small) to 10 (fast) [default: 4]
+      --avif-depth <N>        AVIF bit depth, 8 or 10 [default: 10]
+      --avif-color <MODEL>    ycbcr or rgb, always with 4:4:4 chroma [default: ycbcr]
+      --jxl                   Also generate a .jxl next to each output; unresized JPEGs are
+                              transcoded losslessly and stay reconstructable
+      --jxl-q <N>             JPEG XL quality for pixel encodes, 100 is lossless [default: 90]
+      --jxl-effort <N>        JPEG XL effort, 1 (fast) to 9 (small) [default: 7]
+      --jxl-no-transcode      Encode JPEGs to JPEG XL from pixels instead of transcoding
+      --jxl-strip-metadata    Apply --metadata to transcoded JPEGs too; the original can then
+                              no longer be rebuilt byte for byte
+      --max-width <PX>        Resize images wider than PX
+      --max-height <PX>       Resize images taller than PX
+      --fit <MODE>            contain, cover or exact [default: contain]
+      --filter <FILTER>       nearest, triangle, catmullrom, gaussian or lanczos3 [default: lanczos3]
+      --allow-upscale         Let --fit enlarge images smaller than the box
+      --widths <W,...>        Also write WebP/AVIF variants at these widths (name-640.webp)
+      --densities <N,...>     Also write WebP/AVIF density variants, e.g. 1,2,3 (name@2x.webp)
+      --base-width <PX>       1x width for --densities [default: width / largest density]
+      --target-dssim <X>      Pick the lowest JPEG/WebP/AVIF quality whose DSSIM stays under X
+      --target-ssim <X>       Same as --target-dssim, given as a minimum SSIM (0-1)
+      --jpg-max-kb <N>        Keep each JPEG under N kB by lowering quality
+      --webp-max-kb <N>       Keep each WebP under N kB by lowering quality
+      --avif-max-kb <N>       Keep each AVIF under N kB by lowering quality
+      --budget-min-q <N>      Lowest quality the size budgets may use [default: 30]
+      --budget-downscale      Also shrink the image when the minimum quality is still too big
+      --metadata <POLICY>     strip drops EXIF, XMP and ICC, copyright keeps EXIF copyright/artist
+                              and ICC, all keeps EXIF, XMP and ICC; AVIF is always stripped
+                              [default: strip]
+      --icc <POLICY>          preserve embeds the source ICC profile (AVIF and JPEG XL pixel
+                              encodes are converted to sRGB instead), srgb converts the pixels
+                              and drops it [default: srgb with --metadata strip, else preserve]
+      --svg-precision <N>     Digits kept in SVG coordinates, 1-8 [default: 3]
+      --svg-raster <W,...>    Also render SVGs at these widths (name-64.png)
+      --svg-raster-format <F> png, webp or both [default: png]
+      --no-optimize-original  Skip re-encoding the original format
+      --replace               Overwrite the source files in place
+      --output-dir <DIR>      Write results into DIR instead of next to the sources
+      --dry-run               Encode in memory only and report projected savings
+      --sample <N>            With --dry-run, estimate from N evenly spaced files
+      --backup                With --replace, keep a copy of every original before overwriting
+      --backup-dir <DIR>      Where backups are stored [default: .originals in the first PATH]
+      --backup-max-age <DAYS> Delete backup runs older than DAYS, 0 keeps them [default: 30]
+      --backup-max-mb <MB>    Delete the oldest backup runs past MB in total, 0 for no limit;
+                              the current run is always kept [default: 2048]
+      --undo                  Restore the originals of the last backed-up run and exit
+      --undo-run <ID>         Restore a specific backed-up run instead of the last one
+      --manifest              Skip files already optimized with the same settings
+      --assets <FILE>         Write a JSON manifest of every generated file after the run
+      --picture               With --assets, include a <picture> snippet for each image
+      --report <FILE>         Write a per-file report (.json or .csv) after the run
+      --min-saving <PCT>      Keep the original unless re-encoding saves at least PCT% [default: 0]
+  -h, --help                  Print this help";
+
+const DEFAULT_BACKUP_DIR: &str = ".originals";
+
+struct StdoutReporter;
+
+impl ProgressReporter for StdoutReporter {
+    fn report(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Status(message) => println!("{}", message),
+            ProgressEvent::Progress(p) if p.done > 0 => {
+                println!("[{}/{}] {}", p.done, p.total, p.current_file)
+            }
+            ProgressEvent::Error(e) => eprintln!("error: {}: {}", e.path, e.error),
+            _ => {}
+        }
+    }
+}
+
+struct CliArgs {
+    config: OptimizeConfig,
+    report: Option<PathBuf>,
+    undo: Option<Option<String>>,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Option<CliArgs>, String> {
+    let mut config = OptimizeConfig::default();
+    let mut report = None;
+    let mut undo = None;
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        let it = &mut iter;
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--jpg-q" => config.jpg_q = num_arg(&arg, it, 1..=100)?,
+            "--jpg-subsampling" => {
+                config.jpg_subsampling = match str_arg(&arg, it)?.as_str() {
+                    "444" | "4:4:4" => ChromaSubsampling::Yuv444,
+                    "422" | "4:2:2" => ChromaSubsampling::Yuv422,
+                    "420" | "4:2:0" => ChromaSubsampling::Yuv420,
+                    other => return Err(format!("Unknown --jpg-subsampling: {}", other)),
+                }
+            }
+            "--jpg-lossless" => config.jpg_lossless = true,
+            "--jpg-baseline" => config.jpg_progressive = false,
+            "--jpg-no-trellis" => config.jpg_trellis = false,
+            "--jpg-smoothing" => config.jpg_smoothing = num_arg(&arg, it, 0..=100)?,
+            "--png-min" => config.png_min = num_arg(&arg, it, 1..=100)?,
+            "--png-max" => config.png_max = num_arg(&arg, it, 1..=100)?,
+            "--webp" => config.webp = true,
+            "--avif" => config.avif = true,
+            "--webp-q" => config.webp_quality = num_arg(&arg, it, 0.0..=100.0)?,
+            "--webp-lossless" => {
+                config.webp_lossless = match str_arg(&arg, it)?.as_str() {
+                    "auto" => WebpLossless::Auto,
+                    "always" => WebpLossless::Always,
+                    "never" => WebpLossless::Never,
+                    other => return Err(format!("Unknown --webp-lossless mode: {}", other)),
+                }
+            }
+            "--webp-near-lossless" => config.webp_near_lossless = num_arg(&arg, it, 0..=100)?,
+            "--webp-alpha-q" => config.webp_alpha_quality = num_arg(&arg, it, 0..=100)?,
+            "--webp-method" => config.webp_method = num_arg(&arg, it, 0..=6)?,
+            "--avif-q" => config.avif_quality = num_arg(&arg, it, 1.0..=100.0)?,
+            "--avif-alpha-q" => config.avif_alpha_quality = num_arg(&arg, it, 1.0..=100.0)?,
+            "--avif-speed" => config.avif_speed = num_arg(&arg, it, 1..=10)?,
+            "--avif-depth" => {
+                config.avif_bit_depth = match num_arg(&arg, it, 8..=10)? {
+                    depth @ (8 | 10) => depth,
+                    _ => return Err("--avif-depth expects 8 or 10".to_string()),
+                }
+            }
+            "--avif-color" => {
+                config.avif_color_model = match str_arg(&arg, it)?.as_str() {
+                    "ycbcr" => AvifColorModel::YCbCr,
+                    "rgb" => AvifColorModel::Rgb,
+                    other => return Err(format!("Unknown --avif-color model: {}", other)),
+                }
+            }
+            "--jxl" => config.jxl = true,
+            "--jxl-q" => config.jxl_quality = num_arg(&arg, it, 0.0..=100.0)?,
+            "--jxl-effort" => config.jxl_effort = num_arg(&arg, it, 1..=9)?,
+            "--jxl-no-transcode" => config.jxl_transcode_jpeg = false,
+            "--jxl-strip-metadata" => config.jxl_strip_metadata = true,
+            "--max-width" => config.max_width = Some(num_arg(&arg, it, 1..=u32::MAX)?),
+            "--max-height" => config.max_height = Some(num_arg(&arg, it, 1..=u32::MAX)?),
+            "--fit" => {
+                config.resize_fit = match str_arg(&arg, it)?.as_str() {
+                    "contain" => ResizeFit::Contain,
+                    "cover" => ResizeFit::Cover,
+                    "exact" => ResizeFit::Exact,
+                    other => return Err(format!("Unknown --fit mode: {}", other)),
+                }
+            }
+            "--filter" => {
+                config.resize_filter = match str_arg(&arg, it)?.as_str() {
+                    "nearest" => ResizeFilter::Nearest,
+                    "triangle" => ResizeFilter::Triangle,
+                    "catmullrom" => ResizeFilter::CatmullRom,
+                    "gaussian" => ResizeFilter::Gaussian,
+                    "lanczos3" => ResizeFilter::Lanczos3,
+                    other => return Err(format!("Unknown --filter: {}", other)),
+                }
+            }
+            "--allow-upscale" => config.no_upscale = false,
+            "--widths" => config.variant_widths = list_arg(&arg, it)?,
+            "--densities" => config.variant_densities = list_arg(&arg, it)?,
+            "--base-width" => config.variant_base_width = Some(num_arg(&arg, it, 1..=u32::MAX)?),
+            "--target-dssim" => config.target_dssim = Some(num_arg(&arg, it, 0.0..=1.0)?),
+            "--target-ssim" => {
+                let ssim: f64 = num_arg(&arg, it, 0.01..=1.0)?;
+                config.target_dssim = Some(1.0 / ssim - 1.0);
+            }
+            "--jpg-max-kb" => config.jpg_max_bytes = Some(kb_arg(&arg, it)?),
+            "--webp-max-kb" => config.webp_max_bytes = Some(kb_arg(&arg, it)?),
+            "--avif-max-kb" => config.avif_max_bytes = Some(kb_arg(&arg, it)?),
+            "--budget-min-q" => config.budget_min_quality = num_arg(&arg, it, 1..=100)?,
+            "--budget-downscale" => config.budget_downscale = true,
+            "--metadata" => {
+                config.metadata = match str_arg(&arg, it)?.as_str() {
+                    "strip" => MetadataPolicy::StripAll,
+                    "copyright" => MetadataPolicy::KeepCopyright,
+                    "all" => MetadataPolicy::KeepAll,
+                    other => return Err(format!("Unknown --metadata policy: {}", other)),
+                }
+            }
+            "--icc" => {
+                config.icc = Some(match str_arg(&arg, it)?.as_str() {
+                    "preserve" => IccPolicy::Preserve,
+                    "srgb" => IccPolicy::Srgb,
+                    other => return Err(format!("Unknown --icc policy: {}", other)),
+                })
+            }
+            "--svg-precision" => config.svg_precision = num_arg(&arg, it, 1..=8)?,
+            "--svg-raster" => config.svg_raster_widths = list_arg(&arg, it)?,
+            "--svg-raster-format" => {
+                config.svg_raster_format = match str_arg(&arg, it)?.as_str() {
+                    "png" => SvgRasterFormat::Png,
+                    "webp" => SvgRasterFormat::Webp,
+                    "both" => SvgRasterFormat::Both,
+                    other => return Err(format!("Unknown --svg-raster-format: {}", other)),
+                }
+            }
+            "--no-optimize-original" => config.optimize_original = false,
+            "--replace" => config.replace = true,
+            "--manifest" => config.use_manifest = true,
+            "--backup" => config.backup = true,
+            "--backup-dir" => config.backup_dir = Some(str_arg(&arg, it)?),
+            "--backup-max-age" => config.backup_max_age_days = num_arg(&arg, it, 0..=u64::MAX)?,
+            "--backup-max-mb" => config.backup_max_size_mb = num_arg(&arg, it, 0..=u64::MAX)?,
+            "--undo" => undo = Some(None),
+            "--undo-run" => undo = Some(Some(str_arg(&arg, it)?)),
+            "--dry-run" => config.dry_run = true,
+            "--sample" => config.dry_run_sample = Some(num_arg(&arg, it, 1..=usize::MAX)?),
+            "--min-saving" => config.min_saving_percent = num_arg(&arg, it, 0.0..=99.0)?,
+            "--assets" => config.asset_manifest = Some(str_arg(&arg, it)?),
+            "--picture" => config.picture_snippets = true,
+            "--report" => report = Some(PathBuf::from(str_arg(&arg, it)?)),
+            "--output-dir" => config.output_dir = Some(str_arg(&arg, it)?),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option: {}", flag)),
+            path => config.tasks.push(FileTask {
+                path: path.to_string(),
+                root: path.to_string(),
+            }),
+        }
+    }
+
+    // The default store sits in the first source folder, not the working
+    // directory, so --undo finds it again from wherever it is run.
+    if config.backup_dir.is_none() {
+        let source_root = config.tasks.first().map_or(Path::new("."), |task| {
+            let root = Path::new(&task.root);
+            if root.is_dir() {
+                root
+            } else {
+                root.parent().unwrap_or(root)
+            }
+        });
+        let backup_dir = source_root.join(DEFAULT_BACKUP_DIR);
+        config.backup_dir = Some(backup_dir.to_string_lossy().to_string());
+    }
+    if undo.is_some() {
+        return Ok(Some(CliArgs {
+            config,
+            report,
+            undo,
+        }));
+    }
+
+    if config.tasks.is_empty() {
+        return Err("No input paths given.".to_string());
+    }
+    if config.backup && !config.replace {
+        return Err("--backup only applies together with --replace".to_string());
+    }
+    if config.png_min > config.png_max {
+        return Err("--png-min must not be greater than --png-max".to_string());
+    }
+    if config.picture_snippets && config.asset_manifest.is_none() {
+        return Err("--picture only applies together with --assets".to_string());
+    }
+    if config.dry_run_sample.is_some() && !config.dry_run {
+        return Err("--sample only applies together with --dry-run".to_string());
+    }
+    let has_variants = !config.variant_widths.is_empty() || !config.variant_densities.is_empty();
+    if has_variants && !config.webp && !config.avif {
+        return Err("--widths and --densities need --webp or --avif".to_string());
+    }
+    if !config.webp
+        && !config.avif
+        && !config.jxl
+        && config.svg_raster_widths.is_empty()
+        && !config.optimize_original
+    {
+        return Err(
+            "Nothing to do: enable --webp, --avif, --jxl, --svg-raster or original optimization."
+                .to_string(),
+        );
+    }
+
+    Ok(Some(CliArgs {
+        config,
+        report,
+        undo,
+    }))
+}
+
+fn str_arg(flag: &str, iter: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    iter.next()
+        .ok_or_else(|| format!("{} requires a value", flag))
+}
+
+fn num_arg<T>(
+    flag: &str,
+    iter: &mut impl Iterator<Item = String>,
+    range: RangeInclusive<T>,
+) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let value = str_arg(flag, iter)?;
+    match value.parse::<T>() {
+        Ok(v) if range.contains(&v) => Ok(v),
+        _ => Err(format!(
+            "{} expects a number between {} and {}, got '{}'",
+            flag,
+            range.start(),
+            range.end(),
+            value
+        )),
+    }
+}
+
+fn list_arg(flag: &str, iter: &mut impl Iterator<Item = String>) -> Result<Vec<u32>, String> {
+    let value = str_arg(flag, iter)?;
+    value
+        .split(',')
+        .map(|v| match v.trim().parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!(
+                "{} expects a comma-separated list of positive numbers, got '{}'",
+                flag, value
+            )),
+        })
+        .collect()
+}
+
+fn kb_arg(flag: &str, iter: &mut impl Iterator<Item = String>) -> Result<u64, String> {
+    num_arg(flag, iter, 1..=u64::MAX / 1000).map(|kb| kb * 1000)
+}
+
+fn print_summary(res: &FinalResult) {
+    println!();
+    println!(
+        "Processed {} of {} files in {:.2}s{}",
+        res.processed_files,
+        res.total_files,
+        res.duration_total,
+        if res.is_canceled { " (canceled)" } else { "" }
+    );
+    if res.is_dry_run {
+        println!(
+            "  dry run: nothing was written{}",
+            if res.is_estimate {
+                ", sizes are extrapolated from a sample"
+            } else {
+                ""
+            }
+        );
+    }
+    if let Some(ref path) = res.asset_manifest {
+        println!("  asset manifest written to {}", path);
+    }
+    if let Some(ref id) = res.backup_run {
+        println!("  originals backed up as run {} (undo with --undo)", id);
+    }
+    if res.skipped_files > 0 {
+        println!("  skipped {} unchanged files", res.skipped_files);
+    }
+    if res.total_size_optimized > 0 {
+        println!(
+            "  original: {} -> {} (saved {}, {:.2}s)",
+            format_size(res.total_size_original, DECIMAL),
+            format_size(res.total_size_optimized, DECIMAL),
+            format_size(res.total_size_saved, DECIMAL),
+            res.duration_opt
+        );
+    }
+    if res.lossless_files > 0 {
+        println!(
+            "  lossless: {} JPEG(s), saved {}",
+            res.lossless_files,
+            format_size(res.total_size_saved_lossless, DECIMAL)
+        );
+    }
+    if res.total_size_webp > 0 {
+        println!(
+            "  webp:     {} ({:.2}s)",
+            format_size(res.total_size_webp, DECIMAL),
+            res.duration_webp
+        );
+    }
+    if res.total_size_avif > 0 {
+        println!(
+            "  avif:     {} ({:.2}s)",
+            format_size(res.total_size_avif, DECIMAL),
+            res.duration_avif
+        );
+    }
+    if res.total_size_jxl > 0 {
+        println!(
+            "  jxl:      {} ({:.2}s)",
+            format_size(res.total_size_jxl, DECIMAL),
+            res.duration_jxl
+        );
+    }
+    if let Some(ref avif) = res.avif_settings {
+        println!(
+            "  avif settings: quality {}, alpha {}, speed {}, {}-bit {:?}",
+            avif.quality, avif.alpha_quality, avif.speed, avif.bit_depth, avif.color_model
+        );
+    }
+    if !res.over_budget_files.is_empty() {
+        println!(
+            "{} file(s) over the size budget:",
+            res.over_budget_files.len()
+        );
+        for path in &res.over_budget_files {
+            println!("  {}", path);
+        }
+    }
+    if !res.failed_files.is_empty() {
+        println!("{} error(s):", res.failed_files.len());
+        for f in &res.failed_files {
+            println!("  {}: {}", f.path, f.error);
+        }
+    }
+}
+
+fn run_undo(config: &OptimizeConfig, run_id: Option<&str>) -> ExitCode {
+    let root = match backup_root(config) {
+        Ok(root) => root,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match undo_run(&root, run_id) {
+        Ok(res) => {
+            println!("Restored {} files from run {}", res.restored, res.run_id);
+            for f in &res.failed {
+                eprintln!("error: {}", f);
+            }
+            if res.failed.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let CliArgs {
+        config,
+        report,
+        undo,
+    } = match parse_args(env::args().skip(1).collect()) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Some(run_id) = undo {
+        return run_undo(&config, run_id.as_deref());
+    }
+
+    if let Some(ref dir) = config.output_dir {
+        if !Path::new(dir).is_dir() {
+            eprintln!("error: output directory '{}' does not exist", dir);
+            return ExitCode::from(2);
+        }
+    }
+
+    let should_cancel = Arc::new(AtomicBool::new(false));
+    match perform_optimization(&StdoutReporter, config, should_cancel) {
+        Ok(res) => {
+            print_summary(&res);
+            if let Some(ref path) = report {
+                if let Err(e) = write_report(&res, path) {
+                    eprintln!("error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+                println!("Report written to {}", path.display());
+            }
+            if res.failed_files.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
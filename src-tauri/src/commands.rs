@@ -5,11 +5,13 @@ use base64::{engine::general_purpose, Engine as _};
 use image::ImageFormat;
 use std::io::Cursor;
 use std::sync::atomic::Ordering;
-use tauri::{command, Emitter, State, Window};
+use tauri::{command, Emitter, Manager, State, Window};
 
-use crate::image_ops::ImageCache;
+use crate::backup::undo_run;
+use crate::image_ops::{decode_svg, render_svg, ImageCache};
 use crate::optimizer::perform_optimization;
-use crate::types::{AppState, FinalResult, OptimizeConfig, FileNode};
+use crate::report::write_report;
+use crate::types::{AppState, FinalResult, OptimizeConfig, FileNode, UndoResult};
 
 #[command]
 pub fn get_last_result(state: State<'_, AppState>) -> Option<FinalResult> {
@@ -34,6 +36,16 @@ pub fn get_processing_state(state: State<'_, AppState>) -> bool {
         .unwrap_or_else(|e| e.into_inner())
 }
 
+#[command]
+pub fn export_report(path: String, state: State<'_, AppState>) -> Result<(), String> {
+    let lock = state
+        .last_result
+        .lock()
+        .map_err(|_| "Failed to lock state")?;
+    let result = lock.as_ref().ok_or("No finished run to export.")?;
+    write_report(result, Path::new(&path))
+}
+
 #[command]
 pub fn cancel_optimization(state: State<'_, AppState>) {
     state.should_cancel.store(true, Ordering::Relaxed);
@@ -50,7 +62,16 @@ pub async fn generate_thumbnail(
 
     let path_clone = path.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let img = image::open(&path_clone).map_err(|e| e.to_string())?;
+        let source = Path::new(&path_clone);
+        let img = if source
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+        {
+            let svg = decode_svg(source).map_err(|e| e.to_string())?;
+            render_svg(&svg, 128).map_err(|e| e.to_string())?
+        } else {
+            image::open(source).map_err(|e| e.to_string())?
+        };
         let thumbnail = img.thumbnail(128, 128);
         let mut buffer = Cursor::new(Vec::new());
         thumbnail
@@ -69,7 +90,7 @@ pub async fn generate_thumbnail(
 #[command]
 pub async fn run_optimization(
     window: Window,
-    config: OptimizeConfig,
+    mut config: OptimizeConfig,
     state: State<'_, AppState>,
 ) -> Result<FinalResult, String> {
     {
@@ -91,6 +112,10 @@ pub async fn run_optimization(
         *last_res = None;
     }
 
+    if config.backup && config.backup_dir.is_none() {
+        config.backup_dir = default_backup_dir(&window);
+    }
+
     let _ = window.emit("processing_state_change", true);
 
     let window_clone = window.clone();
@@ -127,11 +152,44 @@ pub async fn run_optimization(
     final_output
 }
 
+#[command]
+pub async fn undo_last_run(
+    window: Window,
+    run_id: Option<String>,
+    backup_dir: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<UndoResult, String> {
+    let is_running = *state
+        .is_processing
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    if is_running {
+        return Err("Optimization is in progress.".to_string());
+    }
+
+    let root = backup_dir
+        .or_else(|| default_backup_dir(&window))
+        .ok_or("Failed to resolve the backup folder.")?;
+
+    tauri::async_runtime::spawn_blocking(move || undo_run(Path::new(&root), run_id.as_deref()))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn default_backup_dir(window: &Window) -> Option<String> {
+    window
+        .path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("backups").to_string_lossy().to_string())
+}
+
 
 fn is_image(path: &Path) -> bool {
     if let Some(ext) = path.extension() {
         let ext_str = ext.to_string_lossy().to_lowercase();
-        return ["jpg", "jpeg", "png"].contains(&ext_str.as_str());
+        return ["jpg", "jpeg", "png", "gif", "webp", "svg"].contains(&ext_str.as_str());
     }
     false
 }
@@ -1,139 +1,1996 @@
-use crate::tools::{get_tool_ref, ToolPath};
-use image::{DynamicImage, GenericImageView};
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, DynamicImage, GenericImageView, ImageFormat, RgbaImage};
+use jpegxl_rs::encode::{EncodeError, EncoderResult, EncoderSpeed, Metadata as JxlMetadata};
+use jpegxl_rs::encoder_builder;
 use moka::future::Cache;
+use resvg::usvg::roxmltree;
+use resvg::{tiny_skia, usvg};
 use rgb::FromSlice;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::Display;
 use std::fs;
+use std::io::{Cursor, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
-use std::process::Command;
+use std::sync::{Arc, OnceLock};
+
+use crate::jpeg_encode::{self, JpegParams};
+use crate::jpeg_lossless;
+use crate::metadata::{apply_orientation, Metadata};
+use crate::types::{
+    AvifColorModel, AvifSettings, ChromaSubsampling, ErrorKind, ErrorStage, FileError, IccPolicy,
+    OptimizeConfig, ResizeFilter, ResizeFit, SizeOutcome, TargetQuality, VariantFormat,
+    WebpLossless,
+};
+use crate::webp_anim;
+
+const AUTO_LOSSLESS_MAX_COLORS: usize = 256;
+const TARGET_QUALITY_RANGE: RangeInclusive<u8> = 20..=95;
+const BUDGET_MIN_DIMENSION: u32 = 16;
+// Editor bookkeeping and RDF metadata; elements and attributes in these
+// namespaces don't affect rendering.
+const SVG_EDITOR_NAMESPACES: &[&str] = &[
+    "http://www.inkscape.org/namespaces/inkscape",
+    "http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd",
+    "http://inkscape.sourceforge.net/DTD/sodipodi-0.dtd",
+    "http://www.bohemiancoding.com/sketch/ns",
+    "http://www.figma.com/figma/ns",
+    "http://www.serif.com/",
+    "http://ns.adobe.com/AdobeIllustrator/10.0/",
+    "http://ns.adobe.com/SaveForWeb/1.0/",
+    "http://www.w3.org/1999/02/22-rdf-syntax-ns#",
+    "http://purl.org/dc/elements/1.1/",
+    "http://creativecommons.org/ns#",
+];
+const SVG_COORDINATE_ATTRIBUTES: &[&str] = &[
+    "d",
+    "points",
+    "x",
+    "y",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "fx",
+    "fy",
+    "width",
+    "height",
+    "viewBox",
+    "stroke-width",
+];
+const SVG_TRANSFORM_ATTRIBUTES: &[&str] = &["transform", "gradientTransform", "patternTransform"];
+// Whitespace inside these is content, not indentation.
+const SVG_TEXT_ELEMENTS: &[&str] = &[
+    "text", "tspan", "textPath", "title", "desc", "style", "script",
+];
+// A group can't hand these to its only child when the child sets them itself,
+// and `style` may set any of them.
+const SVG_GROUP_CONFLICTS: &[&str] = &[
+    "transform",
+    "clip-path",
+    "mask",
+    "filter",
+    "opacity",
+    "style",
+];
+// Children whose attributes mean the same as on the group around them.
+const SVG_GRAPHICS_ELEMENTS: &[&str] = &[
+    "g", "path", "rect", "circle", "ellipse", "line", "polyline", "polygon", "text", "use", "image",
+];
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+static SVG_FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
 
 pub struct ImageCache(pub Cache<String, String>);
 
-pub fn process_jpg(path: &Path, quality: u8) -> u64 {
-    let img = match image::open(path) {
-        Ok(i) => i.to_rgb8(),
-        Err(_) => return fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+pub struct Encoded {
+    pub size: u64,
+    pub outcome: SizeOutcome,
+    pub target: Option<TargetQuality>,
+    pub over_budget: bool,
+    pub lossless: bool,
+    // Set when a byte budget downscaled the output below the decoded size.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl Encoded {
+    pub fn new(size: u64, outcome: SizeOutcome) -> Self {
+        Self {
+            size,
+            outcome,
+            target: None,
+            over_budget: false,
+            lossless: false,
+            dimensions: None,
+        }
+    }
+
+    fn with_target(mut self, target: Option<TargetQuality>) -> Self {
+        self.target = target;
+        self
+    }
+
+    fn over_budget(mut self, over_budget: bool) -> Self {
+        self.over_budget = over_budget;
+        self
+    }
+
+    fn lossless(mut self) -> Self {
+        self.lossless = true;
+        self
+    }
+
+    fn with_dimensions(mut self, dimensions: Option<(u32, u32)>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
+}
+
+fn is_worth_keeping(new_size: u64, original_size: u64, config: &OptimizeConfig) -> bool {
+    let margin = f64::from(config.min_saving_percent.clamp(0.0, 100.0));
+    let limit = original_size as f64 * (1.0 - margin / 100.0);
+    (new_size as f64) < limit
+}
+
+fn write_output(
+    path: &Path,
+    data: &[u8],
+    format: ImageFormat,
+    stage: ErrorStage,
+) -> Result<(), FileError> {
+    write_verified(path, data, stage, |written| is_decodable(written, format))
+}
+
+// Writes next to the target, reads the bytes back and checks they decode before
+// renaming over `path`, so an interrupted run never leaves a truncated file.
+pub fn write_verified(
+    path: &Path,
+    data: &[u8],
+    stage: ErrorStage,
+    is_valid: impl Fn(&[u8]) -> bool,
+) -> Result<(), FileError> {
+    let io_err = |e: &dyn Display| FileError::new(stage, ErrorKind::Io, e);
+
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".optimizing-").suffix(".tmp");
+    #[cfg(unix)]
+    builder.permissions(target_permissions(path));
+
+    let mut tmp = builder.tempfile_in(dir).map_err(|e| io_err(&e))?;
+    tmp.write_all(data)
+        .and_then(|_| tmp.as_file().sync_all())
+        .map_err(|e| io_err(&e))?;
+
+    let written = fs::read(tmp.path()).map_err(|e| io_err(&e))?;
+    if written != data || !is_valid(&written) {
+        return Err(FileError::new(
+            stage,
+            ErrorKind::Verify,
+            &"written file could not be read back",
+        ));
+    }
+
+    tmp.persist(path).map_err(|e| io_err(&e.error))?;
+    Ok(())
+}
+
+fn is_decodable(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Avif => decode_avif(data).is_ok(),
+        _ => image::load_from_memory_with_format(data, format).is_ok(),
+    }
+}
+
+// The image crate decodes AVIF only through a system libdav1d; avif-decode
+// builds its own libaom, so encoded AVIF can be verified and scored in
+// process.
+fn decode_avif(data: &[u8]) -> Result<RgbaImage, String> {
+    fn to_rgba<T: Copy>(img: imgref::ImgVec<T>, pixel: impl Fn(T) -> [u8; 4]) -> Option<RgbaImage> {
+        let rgba = img.as_ref().pixels().flat_map(pixel).collect();
+        RgbaImage::from_raw(img.width() as u32, img.height() as u32, rgba)
+    }
+    let narrow = |v: u16| (v >> 8) as u8;
+
+    let image = avif_decode::Decoder::from_avif(data)
+        .and_then(|decoder| decoder.to_image())
+        .map_err(|e| e.to_string())?;
+    match image {
+        avif_decode::Image::Rgb8(img) => to_rgba(img, |p| [p.r, p.g, p.b, 255]),
+        avif_decode::Image::Rgba8(img) => to_rgba(img, |p| [p.r, p.g, p.b, p.a]),
+        avif_decode::Image::Rgb16(img) => {
+            to_rgba(img, |p| [narrow(p.r), narrow(p.g), narrow(p.b), 255])
+        }
+        avif_decode::Image::Rgba16(img) => to_rgba(img, |p| {
+            [narrow(p.r), narrow(p.g), narrow(p.b), narrow(p.a)]
+        }),
+        avif_decode::Image::Gray8(img) => to_rgba(img, |p| [p.0, p.0, p.0, 255]),
+        avif_decode::Image::Gray16(img) => {
+            to_rgba(img, |p| [narrow(p.0), narrow(p.0), narrow(p.0), 255])
+        }
+    }
+    .ok_or_else(|| "decoded AVIF doesn't match its dimensions".to_string())
+}
+
+// The image crate can't decode JPEG XL, so only the signature is checked:
+// a bare codestream or an ISOBMFF container.
+fn is_jxl(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0x0A]) || data.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n")
+}
+
+#[cfg(unix)]
+fn target_permissions(path: &Path) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path)
+        .map(|m| m.permissions())
+        .unwrap_or_else(|_| fs::Permissions::from_mode(0o644))
+}
+
+// Orientation is baked into the pixels here, so every output is upright
+// whether or not its metadata survives.
+pub fn decode_source(
+    path: &Path,
+    config: &OptimizeConfig,
+) -> Result<(DynamicImage, Metadata), FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Prepare, kind, e);
+
+    let data = fs::read(path).map_err(|e| err(ErrorKind::Io, &e))?;
+    let img = image::load_from_memory(&data).map_err(|e| err(ErrorKind::Decode, &e))?;
+    let mut meta = Metadata::read(&data, config.metadata);
+    let img = apply_orientation(img, meta.orientation);
+    let img = match config.icc.unwrap_or(config.metadata.default_icc()) {
+        IccPolicy::Preserve => img,
+        IccPolicy::Srgb => meta.into_srgb(img),
+    };
+
+    Ok((resize_image(img, config), meta))
+}
+
+pub fn process_jpg(
+    path: &Path,
+    img: &DynamicImage,
+    meta: &Metadata,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    if config.jpg_lossless {
+        return process_jpg_lossless(path, config);
+    }
+
+    let current_size = fs::metadata(path)
+        .map_err(|e| FileError::new(ErrorStage::Original, ErrorKind::Io, &e))?
+        .len();
+
+    let quality = f32::from(config.jpg_q);
+    let encode =
+        |img: &DynamicImage, quality| encode_jpeg(img, config, quality).map(|d| meta.embed(d));
+
+    let (data, target) = match config.target_dssim {
+        Some(max_dssim) => {
+            let (data, target) = search_quality(
+                img,
+                max_dssim,
+                ImageFormat::Jpeg,
+                ErrorStage::Original,
+                encode,
+            )?;
+            (data, Some(target))
+        }
+        None => (encode(img, quality)?, None),
+    };
+    let Fitted {
+        data,
+        target,
+        over_budget,
+        dimensions,
+    } = fit_budget(
+        img,
+        (data, target),
+        config.jpg_max_bytes,
+        quality,
+        config,
+        encode,
+    )?;
+
+    if !is_worth_keeping(data.len() as u64, current_size, config) {
+        return Ok(Encoded::new(current_size, SizeOutcome::KeptOriginal)
+            .with_target(target)
+            .over_budget(config.jpg_max_bytes.is_some_and(|b| current_size > b)));
+    }
+
+    if !config.dry_run {
+        write_output(path, &data, ImageFormat::Jpeg, ErrorStage::Original)?;
+    }
+    Ok(Encoded::new(data.len() as u64, SizeOutcome::Optimized)
+        .with_target(target)
+        .over_budget(over_budget)
+        .with_dimensions(dimensions))
+}
+
+// Works on the file's own coefficients, so resizing, colour conversion,
+// quality targets and byte budgets don't apply to it.
+fn process_jpg_lossless(path: &Path, config: &OptimizeConfig) -> Result<Encoded, FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Original, kind, e);
+
+    let data = fs::read(path).map_err(|e| err(ErrorKind::Io, &e))?;
+    let current_size = data.len() as u64;
+
+    let meta = Metadata::read_lossless(&data, config.metadata);
+    let optimized = jpeg_lossless::transcode(&data, config.jpg_progressive)
+        .map_err(|e| err(ErrorKind::Encode, &e))?;
+    let optimized = meta.embed(optimized);
+
+    if !is_worth_keeping(optimized.len() as u64, current_size, config) {
+        return Ok(Encoded::new(current_size, SizeOutcome::KeptOriginal).lossless());
+    }
+
+    if !config.dry_run {
+        write_output(path, &optimized, ImageFormat::Jpeg, ErrorStage::Original)?;
+    }
+    Ok(Encoded::new(optimized.len() as u64, SizeOutcome::Optimized).lossless())
+}
+
+fn encode_jpeg(
+    img: &DynamicImage,
+    config: &OptimizeConfig,
+    quality: f32,
+) -> Result<Vec<u8>, FileError> {
+    let err = |e: &dyn Display| FileError::new(ErrorStage::Original, ErrorKind::Encode, e);
+
+    let rgb = img.to_rgb8();
+    let (width, height) = rgb.dimensions();
+
+    let chroma = match config.jpg_subsampling {
+        ChromaSubsampling::Yuv444 => (1, 1),
+        ChromaSubsampling::Yuv422 => (2, 1),
+        ChromaSubsampling::Yuv420 => (2, 2),
+    };
+    let params = JpegParams {
+        quality,
+        chroma,
+        smoothing: config.jpg_smoothing.min(100),
+        progressive: config.jpg_progressive,
+        trellis: config.jpg_trellis,
+    };
+    jpeg_encode::encode_rgb(rgb.as_raw(), width, height, &params).map_err(|e| err(&e))
+}
+
+pub fn process_png(
+    path: &Path,
+    img: &DynamicImage,
+    meta: &Metadata,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Original, kind, e);
+
+    let data = fs::read(path).map_err(|e| err(ErrorKind::Io, &e))?;
+    let current_size = data.len() as u64;
+
+    let source = match quantize_png(&img.to_rgba8(), config.png_min, config.png_max) {
+        Some(quantized) => quantized,
+        None if meta.pixels_unchanged() && is_same_size(&data, img) => data,
+        None => {
+            let mut encoded = Vec::new();
+            img.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)
+                .map_err(|e| err(ErrorKind::Encode, &e))?;
+            encoded
+        }
     };
 
+    let optimized = optimize_png(&source).map_err(|e| err(ErrorKind::Encode, &e))?;
+    let optimized = meta.embed(optimized);
+
+    if !is_worth_keeping(optimized.len() as u64, current_size, config) {
+        return Ok(Encoded::new(current_size, SizeOutcome::KeptOriginal));
+    }
+
+    if !config.dry_run {
+        write_output(path, &optimized, ImageFormat::Png, ErrorStage::Original)?;
+    }
+    Ok(Encoded::new(optimized.len() as u64, SizeOutcome::Optimized))
+}
+
+// oxipng strips everything; the chunks the policy keeps are added back after.
+fn optimize_png(data: &[u8]) -> Result<Vec<u8>, oxipng::PngError> {
+    let mut opts = oxipng::Options::from_preset(4);
+    opts.strip = oxipng::StripChunks::All;
+    oxipng::optimize_from_memory(data, &opts)
+}
+
+// Same as `pngquant --quality=min-max --speed=3`: `None` when `min` can't be reached.
+fn quantize_png(img: &RgbaImage, min: u8, max: u8) -> Option<Vec<u8>> {
     let (width, height) = img.dimensions();
-    let pixels = img.as_raw();
 
-    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
-    comp.set_size(width as usize, height as usize);
-    comp.set_quality(quality as f32);
-    comp.set_progressive_mode();
-    comp.set_optimize_scans(true);
+    let mut liq = imagequant::new();
+    liq.set_speed(3).ok()?;
+    liq.set_quality(min, max).ok()?;
+
+    let mut liq_img = liq
+        .new_image(img.as_raw().as_rgba(), width as usize, height as usize, 0.0)
+        .ok()?;
+    let mut res = liq.quantize(&mut liq_img).ok()?;
+    res.set_dithering_level(1.0).ok()?;
+    let (palette, pixels) = res.remapped(&mut liq_img).ok()?;
+
+    let mut plte = Vec::with_capacity(palette.len() * 3);
+    let mut trns = Vec::with_capacity(palette.len());
+    for c in &palette {
+        plte.extend_from_slice(&[c.r, c.g, c.b]);
+        trns.push(c.a);
+    }
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(plte);
+        if trns.iter().any(|&a| a != 255) {
+            encoder.set_trns(trns);
+        }
+        let mut writer = encoder.write_header().ok()?;
+        writer.write_image_data(&pixels).ok()?;
+    }
+
+    Some(out)
+}
 
-    let mut comp = match comp.start_compress(Vec::new()) {
-        Ok(c) => c,
-        Err(_) => return fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+// Whether `img` still has the dimensions stored in `data`, i.e. it wasn't resized.
+fn is_same_size(data: &[u8], img: &DynamicImage) -> bool {
+    source_dimensions(data) == Some(img.dimensions())
+}
+
+fn source_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+// WebP sources are re-encoded into the same file with the WebP settings, so
+// the size rule compares against the file itself.
+pub fn process_webp(
+    path: &Path,
+    img: &DynamicImage,
+    animation: Option<&Animation>,
+    meta: &Metadata,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    let current_size = fs::metadata(path)
+        .map_err(|e| FileError::new(ErrorStage::Original, ErrorKind::Io, &e))?
+        .len();
+    let result = match animation {
+        Some(animation) => generate_animated_webp(animation, path, current_size, config),
+        None => generate_webp(img, meta, path, current_size, config),
     };
+    as_original(result, current_size)
+}
 
-    if comp.write_scanlines(pixels).is_err() {
-        return fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+fn as_original(
+    result: Result<Encoded, FileError>,
+    current_size: u64,
+) -> Result<Encoded, FileError> {
+    match result {
+        Ok(mut res) => {
+            if res.outcome == SizeOutcome::KeptOriginal {
+                res.size = current_size;
+            }
+            Ok(res)
+        }
+        Err(mut e) => {
+            e.stage = ErrorStage::Original;
+            Err(e)
+        }
     }
+}
 
-    let compressed_data = match comp.finish() {
-        Ok(d) => d,
-        Err(_) => return fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+// Lossless like `gifsicle -O`: frames keep their pixels, but unused palette
+// entries are dropped and every frame is LZW-compressed again. The original
+// GIF is never resized.
+pub fn process_gif(path: &Path, config: &OptimizeConfig) -> Result<Encoded, FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Original, kind, e);
+
+    let data = fs::read(path).map_err(|e| err(ErrorKind::Io, &e))?;
+    let current_size = data.len() as u64;
+
+    let optimized = optimize_gif(&data)?;
+
+    if !is_worth_keeping(optimized.len() as u64, current_size, config) {
+        return Ok(Encoded::new(current_size, SizeOutcome::KeptOriginal));
+    }
+
+    if !config.dry_run {
+        write_output(path, &optimized, ImageFormat::Gif, ErrorStage::Original)?;
+    }
+    Ok(Encoded::new(optimized.len() as u64, SizeOutcome::Optimized))
+}
+
+fn optimize_gif(data: &[u8]) -> Result<Vec<u8>, FileError> {
+    let decode_err = |e: &dyn Display| FileError::new(ErrorStage::Original, ErrorKind::Decode, e);
+    let encode_err = |e: &dyn Display| FileError::new(ErrorStage::Original, ErrorKind::Encode, e);
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(|e| decode_err(&e))?;
+
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| decode_err(&e))? {
+        frames.push(frame.clone());
+    }
+
+    let mut global_used = [false; 256];
+    for frame in frames.iter().filter(|f| f.palette.is_none()) {
+        mark_used(frame, &mut global_used);
+    }
+    let (global, global_remap) =
+        compact_palette(decoder.global_palette().unwrap_or_default(), &global_used);
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut out, decoder.width(), decoder.height(), &global)
+            .map_err(|e| encode_err(&e))?;
+        if frames.len() > 1 {
+            encoder
+                .set_repeat(decoder.repeat())
+                .map_err(|e| encode_err(&e))?;
+        }
+
+        for mut frame in frames {
+            let remap = match frame.palette.take() {
+                Some(local) => {
+                    let mut used = [false; 256];
+                    mark_used(&frame, &mut used);
+                    let (local, remap) = compact_palette(&local, &used);
+                    frame.palette = Some(local);
+                    remap
+                }
+                None => global_remap,
+            };
+            frame.buffer = frame
+                .buffer
+                .iter()
+                .map(|&i| remap[usize::from(i)])
+                .collect();
+            frame.transparent = frame.transparent.map(|t| remap[usize::from(t)]);
+            encoder.write_frame(&frame).map_err(|e| encode_err(&e))?;
+        }
+    }
+
+    Ok(out)
+}
+
+fn mark_used(frame: &gif::Frame, used: &mut [bool; 256]) {
+    for &index in frame.buffer.iter() {
+        used[usize::from(index)] = true;
+    }
+    if let Some(t) = frame.transparent {
+        used[usize::from(t)] = true;
+    }
+}
+
+// Keeps only the entries in `used`, renumbered in their original order.
+fn compact_palette(palette: &[u8], used: &[bool; 256]) -> (Vec<u8>, [u8; 256]) {
+    let mut remap = [0u8; 256];
+    let mut compact = Vec::with_capacity(palette.len());
+    for (index, rgb) in palette.chunks_exact(3).enumerate().take(256) {
+        if used[index] {
+            remap[index] = (compact.len() / 3) as u8;
+            compact.extend_from_slice(rgb);
+        }
+    }
+    if compact.is_empty() && !palette.is_empty() {
+        compact.extend_from_slice(&palette[..3]);
+    }
+    (compact, remap)
+}
+
+pub struct Svg {
+    data: Vec<u8>,
+    tree: usvg::Tree,
+}
+
+impl Svg {
+    pub fn dimensions(&self) -> (u32, u32) {
+        let size = self.tree.size().to_int_size();
+        (size.width(), size.height())
+    }
+}
+
+pub fn decode_svg(path: &Path) -> Result<Svg, FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Prepare, kind, e);
+
+    let data = fs::read(path).map_err(|e| err(ErrorKind::Io, &e))?;
+    let tree =
+        usvg::Tree::from_data(&data, &svg_options()).map_err(|e| err(ErrorKind::Decode, &e))?;
+    Ok(Svg { data, tree })
+}
+
+// System fonts are loaded once, on the first SVG; without them usvg drops
+// every `<text>` element.
+fn svg_options() -> usvg::Options<'static> {
+    let fontdb = SVG_FONTS.get_or_init(|| {
+        let mut db = usvg::fontdb::Database::new();
+        db.load_system_fonts();
+        Arc::new(db)
+    });
+    usvg::Options {
+        fontdb: Arc::clone(fontdb),
+        ..Default::default()
+    }
+}
+
+// Minifies the markup itself rather than usvg's tree, so styles, classes,
+// ids, `currentColor` and the viewBox come through exactly as written.
+pub fn process_svg(path: &Path, svg: &Svg, config: &OptimizeConfig) -> Result<Encoded, FileError> {
+    let current_size = svg.data.len() as u64;
+    let Some(minified) = minify_svg(&svg.data, config.svg_precision) else {
+        return Ok(Encoded::new(current_size, SizeOutcome::KeptOriginal));
     };
 
-    if fs::write(path, &compressed_data).is_ok() {
-        compressed_data.len() as u64
+    if !is_worth_keeping(minified.len() as u64, current_size, config) {
+        return Ok(Encoded::new(current_size, SizeOutcome::KeptOriginal));
+    }
+
+    if !config.dry_run {
+        write_verified(path, &minified, ErrorStage::Original, |written| {
+            usvg::Tree::from_data(written, &usvg::Options::default()).is_ok()
+        })?;
+    }
+    Ok(Encoded::new(minified.len() as u64, SizeOutcome::Optimized))
+}
+
+// Drops comments, the XML declaration and doctype, indentation between tags,
+// `<metadata>` and editor namespaces, and rounds coordinates to `precision`
+// digits. `None` when the file isn't UTF-8 XML.
+fn minify_svg(data: &[u8], precision: u8) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).ok()?;
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(text, options).ok()?;
+    // Selectors can depend on the element tree, so groups stay when a
+    // stylesheet is present.
+    let has_stylesheet = doc.descendants().any(|n| {
+        n.has_tag_name((SVG_NS, "style")) || n.pi().is_some_and(|pi| pi.target == "xml-stylesheet")
+    });
+    let options = SvgOptions {
+        precision,
+        collapse_groups: !has_stylesheet,
+    };
+
+    let mut out = String::with_capacity(text.len());
+    for node in doc.root().children() {
+        write_svg_node(&mut out, node, false, &options);
+    }
+    Some(out.into_bytes())
+}
+
+struct SvgOptions {
+    precision: u8,
+    collapse_groups: bool,
+}
+
+fn write_svg_node(out: &mut String, node: roxmltree::Node, keep_space: bool, options: &SvgOptions) {
+    match node.node_type() {
+        roxmltree::NodeType::Element => write_svg_element(out, node, &[], keep_space, options),
+        roxmltree::NodeType::Text => {
+            let text = node.text().unwrap_or_default();
+            if !keep_space && text.trim().is_empty() {
+                return;
+            }
+            // CSS keeps its `<` and `&` as written rather than as entities.
+            let in_style = node
+                .parent_element()
+                .is_some_and(|p| p.tag_name().name() == "style");
+            if in_style && text.contains(['<', '&']) && !text.contains("]]>") {
+                out.push_str("<![CDATA[");
+                out.push_str(text);
+                out.push_str("]]>");
+            } else {
+                push_xml_escaped(out, text, false);
+            }
+        }
+        // Stylesheet links live in processing instructions.
+        roxmltree::NodeType::PI => {
+            if let Some(pi) = node.pi() {
+                out.push_str("<?");
+                out.push_str(pi.target);
+                if let Some(value) = pi.value {
+                    out.push(' ');
+                    out.push_str(value);
+                }
+                out.push_str("?>");
+            }
+        }
+        roxmltree::NodeType::Root | roxmltree::NodeType::Comment => {}
+    }
+}
+
+// `inherited` holds the attributes of groups collapsed into this element.
+fn write_svg_element<'a, 'input>(
+    out: &mut String,
+    node: roxmltree::Node<'a, 'input>,
+    inherited: &[roxmltree::Attribute<'a, 'input>],
+    keep_space: bool,
+    options: &SvgOptions,
+) {
+    let tag = node.tag_name();
+    if tag.name() == "metadata" || tag.namespace().is_some_and(is_editor_namespace) {
+        return;
+    }
+    match collapse_group(node, inherited, keep_space, options) {
+        Collapse::Keep => {}
+        Collapse::Unwrap => {
+            for child in node.children() {
+                write_svg_node(out, child, keep_space, options);
+            }
+            return;
+        }
+        Collapse::Into(child, attributes) => {
+            write_svg_element(out, child, &attributes, keep_space, options);
+            return;
+        }
+    }
+
+    let name = qualified_name(node, tag.namespace(), tag.name());
+    out.push('<');
+    out.push_str(&name);
+
+    for ns in declared_namespaces(node) {
+        match ns.name() {
+            Some(prefix) => {
+                out.push_str(" xmlns:");
+                out.push_str(prefix);
+            }
+            None => out.push_str(" xmlns"),
+        }
+        out.push_str("=\"");
+        push_xml_escaped(out, ns.uri(), true);
+        out.push('"');
+    }
+
+    let mut keep_space = keep_space || SVG_TEXT_ELEMENTS.contains(&tag.name());
+    for attr in inherited.iter().copied().chain(node.attributes()) {
+        let value = match attr.namespace() {
+            Some(uri) if is_editor_namespace(uri) => continue,
+            Some(_) => Cow::Borrowed(attr.value()),
+            None => round_svg_numbers(attr.name(), attr.value(), options.precision),
+        };
+        let attr_name = qualified_name(node, attr.namespace(), attr.name());
+        keep_space |= attr_name == "xml:space" && value == "preserve";
+
+        out.push(' ');
+        out.push_str(&attr_name);
+        out.push_str("=\"");
+        push_xml_escaped(out, &value, true);
+        out.push('"');
+    }
+
+    let open = out.len();
+    out.push('>');
+    for child in node.children() {
+        write_svg_node(out, child, keep_space, options);
+    }
+    if out.len() == open + 1 {
+        out.truncate(open);
+        out.push_str("/>");
     } else {
-        fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+        out.push_str("</");
+        out.push_str(&name);
+        out.push('>');
     }
 }
 
-pub fn process_png(path: &Path, pq: &ToolPath, oxi: &ToolPath, min: u8, max: u8) -> u64 {
-    run_pngquant(path, pq, min, max);
-    run_oxipng(path, oxi);
-    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+enum Collapse<'a, 'input> {
+    Keep,
+    Unwrap,
+    Into(
+        roxmltree::Node<'a, 'input>,
+        Vec<roxmltree::Attribute<'a, 'input>>,
+    ),
 }
 
-fn run_pngquant(path: &Path, tool: &ToolPath, min: u8, max: u8) {
-    #[cfg(target_os = "windows")]
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
+// A `<g>` without attributes is replaced by its children. One with attributes
+// and a single graphics child hands them to that child, unless the child sets
+// any of them or one that combines with them. Groups with an id may be
+// referenced, a group `style` would override the child's own attributes, and
+// declared namespaces or preserved whitespace would be lost, so those stay.
+fn collapse_group<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    inherited: &[roxmltree::Attribute<'a, 'input>],
+    keep_space: bool,
+    options: &SvgOptions,
+) -> Collapse<'a, 'input> {
+    let in_switch = node
+        .parent_element()
+        .is_some_and(|p| p.tag_name().name() == "switch");
+    if !options.collapse_groups
+        || !node.has_tag_name((SVG_NS, "g"))
+        || keep_space
+        || in_switch
+        || declared_namespaces(node).next().is_some()
+    {
+        return Collapse::Keep;
+    }
 
-    let mut cmd = Command::new(get_tool_ref(tool));
-    cmd.args([
-        &format!("--quality={}-{}", min, max),
-        "--speed=3",
-        "--force",
-        "--ext=.png",
-    ])
-    .arg(path);
+    let attributes: Vec<_> = inherited
+        .iter()
+        .copied()
+        .chain(node.attributes())
+        .filter(|attr| !attr.namespace().is_some_and(is_editor_namespace))
+        .collect();
+    if attributes.is_empty() {
+        return Collapse::Unwrap;
+    }
+    if attributes
+        .iter()
+        .any(|attr| attr.namespace().is_some() || ["id", "style"].contains(&attr.name()))
+    {
+        return Collapse::Keep;
+    }
 
-    #[cfg(target_os = "windows")]
-    use std::os::windows::process::CommandExt;
-    #[cfg(target_os = "windows")]
-    cmd.creation_flags(CREATE_NO_WINDOW);
+    let mut children = node.children().filter(|child| {
+        !child.is_comment()
+            && !(child.is_text() && child.text().unwrap_or_default().trim().is_empty())
+    });
+    let child = match (children.next(), children.next()) {
+        (Some(child), None) if child.tag_name().namespace() == Some(SVG_NS) => child,
+        _ => return Collapse::Keep,
+    };
+    let conflicts = child.attributes().any(|attr| {
+        SVG_GROUP_CONFLICTS.contains(&attr.name())
+            || attributes.iter().any(|a| a.name() == attr.name())
+    });
+    if !SVG_GRAPHICS_ELEMENTS.contains(&child.tag_name().name()) || conflicts {
+        return Collapse::Keep;
+    }
+    Collapse::Into(child, attributes)
+}
 
-    let _ = cmd.output();
+// roxmltree lists every namespace in scope; these are the ones new here.
+fn declared_namespaces<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+) -> impl Iterator<Item = &'a roxmltree::Namespace<'input>> {
+    let parent = node.parent();
+    node.namespaces().filter(move |ns| {
+        let inherited = parent.is_some_and(|parent| {
+            parent
+                .namespaces()
+                .any(|p| p.name() == ns.name() && p.uri() == ns.uri())
+        });
+        !inherited && ns.name() != Some("xml") && !is_editor_namespace(ns.uri())
+    })
 }
 
-fn run_oxipng(path: &Path, tool: &ToolPath) {
-    #[cfg(target_os = "windows")]
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
+fn is_editor_namespace(uri: &str) -> bool {
+    SVG_EDITOR_NAMESPACES.contains(&uri)
+}
 
-    let mut cmd = Command::new(get_tool_ref(tool));
-    cmd.args(["-o", "4", "--strip", "all", "-t", "1"]).arg(path);
+fn qualified_name<'a>(
+    node: roxmltree::Node,
+    namespace: Option<&str>,
+    name: &'a str,
+) -> Cow<'a, str> {
+    let prefix = match namespace {
+        Some(XML_NS) => Some("xml"),
+        Some(uri) => node.lookup_prefix(uri),
+        None => None,
+    };
+    match prefix {
+        Some(prefix) => Cow::Owned(format!("{}:{}", prefix, name)),
+        None => Cow::Borrowed(name),
+    }
+}
 
-    #[cfg(target_os = "windows")]
-    use std::os::windows::process::CommandExt;
-    #[cfg(target_os = "windows")]
-    cmd.creation_flags(CREATE_NO_WINDOW);
+// Escapes only what the context needs: quotes in double-quoted attribute
+// values, and `>` in text only where it would close a `]]>`.
+fn push_xml_escaped(out: &mut String, text: &str, in_attribute: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' if in_attribute => out.push_str("&quot;"),
+            '>' if !in_attribute && out.ends_with("]]") => out.push_str("&gt;"),
+            _ => out.push(c),
+        }
+    }
+}
 
-    let _ = cmd.output();
+// Arc flags may be written without separators ("a5 5 0 011 1"), which can't be
+// told apart from numbers without a full path parser, so path data with arcs
+// is left alone. A number is only replaced when that makes it shorter.
+fn round_svg_numbers<'a>(name: &str, value: &'a str, precision: u8) -> Cow<'a, str> {
+    // Transforms get a floor of 5 digits: a rounded scale factor skews the
+    // whole subtree, not just one point.
+    let precision = if SVG_TRANSFORM_ATTRIBUTES.contains(&name) {
+        precision.max(5)
+    } else if SVG_COORDINATE_ATTRIBUTES.contains(&name) {
+        precision
+    } else {
+        return Cow::Borrowed(value);
+    };
+    if name == "d" && value.contains(['a', 'A']) {
+        return Cow::Borrowed(value);
+    }
+
+    let bytes = value.as_bytes();
+    let mut out = String::with_capacity(value.len());
+    let (mut copied, mut i) = (0, 0);
+    let mut previous: Option<(usize, bool)> = None;
+    while i < bytes.len() {
+        let end = number_end(bytes, i);
+        if end == i {
+            i += 1;
+            continue;
+        }
+
+        let token = &value[i..end];
+        let rounded = token
+            .parse::<f64>()
+            .ok()
+            .map(|n| format_svg_number(n, precision))
+            .filter(|r| r.len() < token.len());
+        let number = rounded.as_deref().unwrap_or(token);
+
+        out.push_str(&value[copied..i]);
+        // "1.04.5" is two numbers; once the first loses its dot they'd merge.
+        if previous.is_some_and(|(prev_end, had_dot)| prev_end == i && !had_dot)
+            && number.starts_with('.')
+        {
+            out.push(' ');
+        }
+        out.push_str(number);
+        previous = Some((end, number.contains('.')));
+        copied = end;
+        i = end;
+    }
+    if copied == 0 {
+        return Cow::Borrowed(value);
+    }
+    out.push_str(&value[copied..]);
+    Cow::Owned(out)
 }
 
-pub fn generate_webp(img: &DynamicImage, path: &Path, quality: f32) -> u64 {
-    let webp_path = path.with_extension("webp");
+// End of the number starting at `start`, or `start` when there is none. An
+// `e` only counts as an exponent when digits follow, so `1em` stays a unit.
+fn number_end(bytes: &[u8], start: usize) -> usize {
+    let is_digit = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_digit);
+
+    let mut i = start;
+    if matches!(bytes.get(i), Some(b'+' | b'-')) {
+        i += 1;
+    }
+    let int_start = i;
+    while is_digit(i) {
+        i += 1;
+    }
+    let mut digits = i - int_start;
+    if bytes.get(i) == Some(&b'.') {
+        let mut j = i + 1;
+        while is_digit(j) {
+            j += 1;
+        }
+        digits += j - (i + 1);
+        if digits > 0 {
+            i = j;
+        }
+    }
+    if digits == 0 {
+        return start;
+    }
+
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        let mut j = i + 1;
+        if matches!(bytes.get(j), Some(b'+' | b'-')) {
+            j += 1;
+        }
+        if is_digit(j) {
+            while is_digit(j) {
+                j += 1;
+            }
+            i = j;
+        }
+    }
+    i
+}
+
+// Fixed point without trailing zeros or a leading zero: 0.500 becomes .5.
+fn format_svg_number(value: f64, precision: u8) -> String {
+    let mut s = format!("{:.*}", usize::from(precision), value);
+    if s.contains('.') {
+        let trimmed = s.trim_end_matches('0').trim_end_matches('.').len();
+        s.truncate(trimmed);
+    }
+    if s == "-0" {
+        s = "0".to_string();
+    }
+    if let Some(rest) = s.strip_prefix("0.") {
+        s = format!(".{}", rest);
+    } else if let Some(rest) = s.strip_prefix("-0.") {
+        s = format!("-.{}", rest);
+    }
+    s
+}
+
+// Scales the SVG to `width` keeping its aspect ratio.
+pub fn render_svg(svg: &Svg, width: u32) -> Result<DynamicImage, FileError> {
+    let err = |e: &dyn Display| FileError::new(ErrorStage::Raster, ErrorKind::Decode, e);
+
+    let size = svg.tree.size();
+    let scale = width as f32 / size.width();
+    let height = (size.height() * scale).round().max(1.0) as u32;
+
+    let mut pixmap =
+        tiny_skia::Pixmap::new(width, height).ok_or_else(|| err(&"raster size is too large"))?;
+    resvg::render(
+        &svg.tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia works in premultiplied alpha; the encoders expect straight.
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| err(&"rendered buffer has the wrong size"))
+}
+
+// Rasters are always written: unlike the other outputs there is no original
+// in the same kind to compare their size against.
+pub fn generate_raster(
+    img: &DynamicImage,
+    format: VariantFormat,
+    path: &Path,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    let err = |e: &dyn Display| FileError::new(ErrorStage::Raster, ErrorKind::Encode, e);
+    let raster_path = path.with_extension(format.extension());
+
+    let (data, image_format) = match format {
+        VariantFormat::Png => {
+            let source = match quantize_png(&img.to_rgba8(), config.png_min, config.png_max) {
+                Some(quantized) => quantized,
+                None => {
+                    let mut encoded = Vec::new();
+                    img.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)
+                        .map_err(|e| err(&e))?;
+                    encoded
+                }
+            };
+            (
+                optimize_png(&source).map_err(|e| err(&e))?,
+                ImageFormat::Png,
+            )
+        }
+        VariantFormat::Webp => {
+            let lossless = match config.webp_lossless {
+                WebpLossless::Always => true,
+                WebpLossless::Never => false,
+                WebpLossless::Auto => has_few_colors(img),
+            };
+            let data = encode_webp(img, config, lossless, config.webp_quality)?;
+            (data, ImageFormat::WebP)
+        }
+        VariantFormat::Avif => {
+            let settings = AvifSettings::from_config(config);
+            (
+                encode_avif(img, &settings, settings.quality)?,
+                ImageFormat::Avif,
+            )
+        }
+    };
+
+    if !config.dry_run {
+        write_output(&raster_path, &data, image_format, ErrorStage::Raster)?;
+    }
+    Ok(Encoded::new(data.len() as u64, SizeOutcome::Optimized))
+}
+
+pub fn resize_image(img: DynamicImage, config: &OptimizeConfig) -> DynamicImage {
     let (width, height) = img.dimensions();
+    // A single bound scales the other side with it, so the image can still
+    // grow past its own size when upscaling is allowed.
+    let scaled = |side: u32, bound: u32, other: u32| {
+        (u64::from(side) * u64::from(bound) / u64::from(other.max(1))).max(1) as u32
+    };
+    let (max_width, max_height) = match (config.max_width, config.max_height) {
+        (Some(w), Some(h)) => (w.max(1), h.max(1)),
+        (Some(w), None) => (w.max(1), scaled(height, w.max(1), width)),
+        (None, Some(h)) => (scaled(width, h.max(1), height), h.max(1)),
+        (None, None) => return img,
+    };
+    let filter = filter_type(config);
+
+    // Cover and exact need a full box; with a single bound they behave like contain.
+    let fit = match (config.max_width, config.max_height) {
+        (Some(_), Some(_)) => config.resize_fit,
+        _ => ResizeFit::Contain,
+    };
 
-    let memory = match img {
-        DynamicImage::ImageRgba8(buf) => {
-            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode(quality)
+    match fit {
+        ResizeFit::Contain => {
+            if config.no_upscale && width <= max_width && height <= max_height {
+                return img;
+            }
+            img.resize(max_width, max_height, filter)
+        }
+        ResizeFit::Cover => {
+            let scale = f64::max(
+                f64::from(max_width) / f64::from(width),
+                f64::from(max_height) / f64::from(height),
+            );
+            // Without upscaling, shrink the box instead so the crop keeps its aspect.
+            let (box_width, box_height) = if config.no_upscale && scale > 1.0 {
+                (
+                    (f64::from(max_width) / scale).round().max(1.0) as u32,
+                    (f64::from(max_height) / scale).round().max(1.0) as u32,
+                )
+            } else {
+                (max_width, max_height)
+            };
+            if (box_width, box_height) == (width, height) {
+                return img;
+            }
+            img.resize_to_fill(box_width, box_height, filter)
+        }
+        ResizeFit::Exact => {
+            let (new_width, new_height) = if config.no_upscale {
+                (max_width.min(width), max_height.min(height))
+            } else {
+                (max_width, max_height)
+            };
+            if (new_width, new_height) == (width, height) {
+                return img;
+            }
+            img.resize_exact(new_width, new_height, filter)
         }
-        DynamicImage::ImageRgb8(buf) => {
-            webp::Encoder::from_rgb(buf.as_raw(), width, height).encode(quality)
+    }
+}
+
+fn filter_type(config: &OptimizeConfig) -> FilterType {
+    match config.resize_filter {
+        ResizeFilter::Nearest => FilterType::Nearest,
+        ResizeFilter::Triangle => FilterType::Triangle,
+        ResizeFilter::CatmullRom => FilterType::CatmullRom,
+        ResizeFilter::Gaussian => FilterType::Gaussian,
+        ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+    }
+}
+
+pub struct VariantSpec {
+    pub descriptor: String,
+    pub suffix: String,
+    pub width: u32,
+}
+
+// Explicit widths become `-640` / `640w`, densities `@2x` / `2x` of the base
+// width, which defaults to the image width divided by the largest density.
+pub fn variant_specs(width: u32, config: &OptimizeConfig) -> Vec<VariantSpec> {
+    let fits = |w: u32| w > 0 && (w <= width || !config.no_upscale);
+
+    let mut specs: Vec<VariantSpec> = config
+        .variant_widths
+        .iter()
+        .filter(|&&w| fits(w))
+        .map(|&w| VariantSpec {
+            descriptor: format!("{}w", w),
+            suffix: format!("-{}", w),
+            width: w,
+        })
+        .collect();
+
+    let max_density = config.variant_densities.iter().copied().max().unwrap_or(1);
+    let base = config
+        .variant_base_width
+        .unwrap_or(width / max_density.max(1));
+    specs.extend(
+        config
+            .variant_densities
+            .iter()
+            .filter_map(|&d| Some((d, base.checked_mul(d).filter(|&w| fits(w))?)))
+            .map(|(d, w)| VariantSpec {
+                descriptor: format!("{}x", d),
+                suffix: format!("@{}x", d),
+                width: w,
+            }),
+    );
+
+    specs
+}
+
+pub fn resize_to_width(img: &DynamicImage, width: u32, config: &OptimizeConfig) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    let height = ((u64::from(h) * u64::from(width)) / u64::from(w).max(1)).max(1) as u32;
+    img.resize_exact(width, height, filter_type(config))
+}
+
+pub fn generate_webp(
+    img: &DynamicImage,
+    meta: &Metadata,
+    path: &Path,
+    original_size: u64,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    let webp_path = path.with_extension("webp");
+
+    let lossless = match config.webp_lossless {
+        WebpLossless::Always => true,
+        WebpLossless::Never => false,
+        WebpLossless::Auto => is_palette_source(path) && has_few_colors(img),
+    };
+    let encode = |img: &DynamicImage, quality| {
+        encode_webp(img, config, lossless, quality).map(|d| meta.embed(d))
+    };
+    // Lossless output can't trade quality for bytes, so the budget search is lossy.
+    let encode_lossy = |img: &DynamicImage, quality| {
+        encode_webp(img, config, false, quality).map(|d| meta.embed(d))
+    };
+
+    let (data, target) = match config.target_dssim {
+        Some(max_dssim) if !lossless => {
+            let (data, target) =
+                search_quality(img, max_dssim, ImageFormat::WebP, ErrorStage::Webp, encode)?;
+            (data, Some(target))
         }
+        _ => (encode(img, config.webp_quality)?, None),
+    };
+    let Fitted {
+        data,
+        target,
+        over_budget,
+        dimensions,
+    } = fit_budget(
+        img,
+        (data, target),
+        config.webp_max_bytes,
+        config.webp_quality,
+        config,
+        encode_lossy,
+    )?;
+
+    if !is_worth_keeping(data.len() as u64, original_size, config) {
+        return Ok(Encoded::new(0, SizeOutcome::KeptOriginal).with_target(target));
+    }
+
+    if !config.dry_run {
+        write_output(&webp_path, &data, ImageFormat::WebP, ErrorStage::Webp)?;
+    }
+    Ok(Encoded::new(data.len() as u64, SizeOutcome::Optimized)
+        .with_target(target)
+        .over_budget(over_budget)
+        .with_dimensions(dimensions))
+}
+
+fn encode_webp(
+    img: &DynamicImage,
+    config: &OptimizeConfig,
+    lossless: bool,
+    quality: f32,
+) -> Result<Vec<u8>, FileError> {
+    let err = |e: &dyn Display| FileError::new(ErrorStage::Webp, ErrorKind::Encode, e);
+
+    let webp_config = webp_config(config, lossless, quality)
+        .ok_or_else(|| err(&"failed to initialise the WebP encoder"))?;
+
+    let (width, height) = img.dimensions();
+    let rgba;
+    let encoder = match img {
+        DynamicImage::ImageRgba8(buf) => webp::Encoder::from_rgba(buf.as_raw(), width, height),
+        DynamicImage::ImageRgb8(buf) => webp::Encoder::from_rgb(buf.as_raw(), width, height),
         _ => {
-            let buf = img.to_rgba8();
-            webp::Encoder::from_rgba(buf.as_raw(), width, height).encode(quality)
+            rgba = img.to_rgba8();
+            webp::Encoder::from_rgba(rgba.as_raw(), width, height)
         }
     };
 
-    if fs::write(&webp_path, &*memory).is_ok() {
-        memory.len() as u64
+    encoder
+        .encode_advanced(&webp_config)
+        .map(|memory| memory.to_vec())
+        .map_err(|e| err(&format!("{:?}", e)))
+}
+
+// In lossless mode libwebp reads `quality` as compression effort, and
+// `near_lossless` only applies there (100 turns it off).
+fn webp_config(config: &OptimizeConfig, lossless: bool, quality: f32) -> Option<webp::WebPConfig> {
+    let mut webp_config = webp::WebPConfig::new().ok()?;
+    webp_config.lossless = i32::from(lossless);
+    webp_config.quality = quality.clamp(0.0, 100.0);
+    webp_config.method = i32::from(config.webp_method.min(6));
+    webp_config.alpha_quality = i32::from(config.webp_alpha_quality.min(100));
+    webp_config.near_lossless = i32::from(config.webp_near_lossless.min(100));
+    Some(webp_config)
+}
+
+// Composited frames with their display time in ms, already resized.
+pub struct Animation {
+    pub frames: Vec<(RgbaImage, u32)>,
+    pub loop_count: u16,
+}
+
+// `None` for still GIFs and WebPs, which take the still-image path.
+pub fn decode_animation(
+    path: &Path,
+    config: &OptimizeConfig,
+) -> Result<Option<Animation>, FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Prepare, kind, e);
+
+    let data = fs::read(path).map_err(|e| err(ErrorKind::Io, &e))?;
+    let is_gif = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+
+    let (frames, loop_count) = if is_gif {
+        let frames = GifDecoder::new(Cursor::new(&data))
+            .and_then(|decoder| decoder.into_frames().collect_frames())
+            .map_err(|e| err(ErrorKind::Decode, &e))?;
+        // GIF counts repeats after the first play, WebP counts plays.
+        let loop_count = match gif::DecodeOptions::new()
+            .read_info(data.as_slice())
+            .map(|decoder| decoder.repeat())
+        {
+            Ok(gif::Repeat::Finite(n)) => n.saturating_add(1),
+            _ => 0,
+        };
+        (frames, loop_count)
     } else {
-        0
+        let decoder =
+            WebPDecoder::new(Cursor::new(&data)).map_err(|e| err(ErrorKind::Decode, &e))?;
+        if !decoder.has_animation() {
+            return Ok(None);
+        }
+        let frames = decoder
+            .into_frames()
+            .collect_frames()
+            .map_err(|e| err(ErrorKind::Decode, &e))?;
+        (frames, webp_loop_count(&data))
+    };
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let (num, den) = frame.delay().numer_denom_ms();
+            // Browsers show GIF delays of 10ms or less at 100ms; WebP would not.
+            let delay = match num / den.max(1) {
+                0..=10 if is_gif => 100,
+                ms => ms,
+            };
+            let img = resize_image(DynamicImage::ImageRgba8(frame.into_buffer()), config);
+            (img.into_rgba8(), delay)
+        })
+        .collect();
+
+    Ok(Some(Animation { frames, loop_count }))
+}
+
+// Reads the loop count from the ANIM chunk; 0 (forever) when there is none.
+fn webp_loop_count(data: &[u8]) -> u16 {
+    let mut offset = 12;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if &header[..4] == b"ANIM" {
+            return data
+                .get(offset + 12..offset + 14)
+                .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]));
+        }
+        offset = offset.saturating_add(8 + size + size % 2);
+    }
+    0
+}
+
+// ravif only writes still images, so animations get WebP output alone.
+pub fn generate_animated_webp(
+    animation: &Animation,
+    path: &Path,
+    original_size: u64,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    let err = |e: &dyn Display| FileError::new(ErrorStage::Webp, ErrorKind::Encode, e);
+    let webp_path = path.with_extension("webp");
+
+    // GIF frames are palette images, so Auto keeps them lossless like
+    // small-palette PNGs; animated WebP sources stay lossy.
+    let lossless = match config.webp_lossless {
+        WebpLossless::Always => true,
+        WebpLossless::Never => false,
+        WebpLossless::Auto => is_palette_source(path),
+    };
+    let webp_config = webp_config(config, lossless, config.webp_quality)
+        .ok_or_else(|| err(&"failed to initialise the WebP encoder"))?;
+    let data = webp_anim::encode(&animation.frames, animation.loop_count, &webp_config)
+        .map_err(|e| err(&e))?;
+
+    let size = data.len() as u64;
+    if !is_worth_keeping(size, original_size, config) {
+        return Ok(Encoded::new(0, SizeOutcome::KeptOriginal));
     }
+
+    if !config.dry_run {
+        write_output(&webp_path, &data, ImageFormat::WebP, ErrorStage::Webp)?;
+    }
+    Ok(Encoded::new(size, SizeOutcome::Optimized))
+}
+
+// Sources that are usually flat graphics rather than photos.
+fn is_palette_source(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("gif"))
 }
 
-pub fn generate_avif(img: &DynamicImage, path: &Path) -> u64 {
+fn has_few_colors(img: &DynamicImage) -> bool {
+    let mut colors = HashSet::new();
+    img.to_rgba8().pixels().all(|p| {
+        colors.insert(p.0);
+        colors.len() <= AUTO_LOSSLESS_MAX_COLORS
+    })
+}
+
+pub fn generate_avif(
+    img: &DynamicImage,
+    meta: &Metadata,
+    path: &Path,
+    original_size: u64,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
     let avif_path = path.with_extension("avif");
+
+    // ravif has no way to attach EXIF or ICC, so AVIF output is always
+    // stripped; orientation was already applied to the pixels. A preserved
+    // profile is converted into the pixels instead so colours don't shift.
+    let converted = meta.to_srgb(img);
+    let img = converted.as_ref().unwrap_or(img);
+
+    let settings = AvifSettings::from_config(config);
+    let encode = |img: &DynamicImage, quality| encode_avif(img, &settings, quality);
+
+    let (data, target) = match config.target_dssim {
+        Some(max_dssim) => {
+            let (data, target) =
+                search_quality(img, max_dssim, ImageFormat::Avif, ErrorStage::Avif, encode)?;
+            (data, Some(target))
+        }
+        None => (encode(img, settings.quality)?, None),
+    };
+    let Fitted {
+        data,
+        target,
+        over_budget,
+        dimensions,
+    } = fit_budget(
+        img,
+        (data, target),
+        config.avif_max_bytes,
+        settings.quality,
+        config,
+        encode,
+    )?;
+
+    let size = data.len() as u64;
+    if !is_worth_keeping(size, original_size, config) {
+        return Ok(Encoded::new(0, SizeOutcome::KeptOriginal).with_target(target));
+    }
+
+    if !config.dry_run {
+        write_output(&avif_path, &data, ImageFormat::Avif, ErrorStage::Avif)?;
+    }
+    Ok(Encoded::new(size, SizeOutcome::Optimized)
+        .with_target(target)
+        .over_budget(over_budget)
+        .with_dimensions(dimensions))
+}
+
+// Reads the size of an image written by an earlier run. AVIF can't be decoded
+// here, so its size comes from the `ispe` property of the primary item.
+pub fn output_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let is_avif = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("avif"));
+    if !is_avif {
+        return image::image_dimensions(path).ok();
+    }
+
+    let data = fs::read(path).ok()?;
+    let start = data.windows(4).position(|w| w == b"ispe")?;
+    // Box type, then a version/flags word, then big-endian width and height.
+    let field = |at: usize| {
+        let bytes = data.get(start + at..start + at + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    };
+    Some((field(8)?, field(12)?))
+}
+
+fn encode_avif(
+    img: &DynamicImage,
+    settings: &AvifSettings,
+    quality: f32,
+) -> Result<Vec<u8>, FileError> {
     let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let src_img = imgref::Img::new(rgba.as_raw().as_rgba(), width as usize, height as usize);
+
+    let bit_depth = match settings.bit_depth {
+        8 => ravif::BitDepth::Eight,
+        _ => ravif::BitDepth::Ten,
+    };
+    let color_model = match settings.color_model {
+        AvifColorModel::YCbCr => ravif::ColorModel::YCbCr,
+        AvifColorModel::Rgb => ravif::ColorModel::RGB,
+    };
+
+    ravif::Encoder::new()
+        .with_quality(quality.clamp(1.0, 100.0))
+        .with_speed(settings.speed)
+        .with_alpha_quality(settings.alpha_quality)
+        .with_bit_depth(bit_depth)
+        .with_internal_color_model(color_model)
+        .encode_rgba(src_img)
+        .map(|encoded| encoded.avif_file)
+        .map_err(|e| FileError::new(ErrorStage::Avif, ErrorKind::Encode, &e))
+}
+
+// Unresized JPEG sources are transcoded losslessly as they are, so the JPEG can
+// be rebuilt bit for bit from the .jxl; the metadata policy only applies to
+// them on request. Anything else is encoded from pixels. There's no JXL
+// decoder to score against, so no DSSIM or budget search.
+pub fn generate_jxl(
+    img: &DynamicImage,
+    meta: &Metadata,
+    src: &Path,
+    path: &Path,
+    original_size: u64,
+    config: &OptimizeConfig,
+) -> Result<Encoded, FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(ErrorStage::Jxl, kind, e);
+    let jxl_path = path.with_extension("jxl");
+
+    // The orientation tag and profile travel with an untouched file, so only a
+    // resize rules the transcode out. Once they are stripped, the pixels must
+    // not have depended on them either.
+    let transcode = config.jxl_transcode_jpeg
+        && is_jpeg(src)
+        && (!config.jxl_strip_metadata || meta.pixels_unchanged());
+    let jpeg = if transcode {
+        let data = fs::read(src).map_err(|e| err(ErrorKind::Io, &e))?;
+        let (width, height) = img.dimensions();
+        let upright = if meta.orientation >= 5 {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        (source_dimensions(&data) == Some(upright)).then_some(data)
+    } else {
+        None
+    };
+
+    let lossless = jpeg.is_some();
+    let data = match jpeg {
+        Some(jpeg) if config.jxl_strip_metadata => {
+            transcode_jxl(&meta.replace_in_jpeg(jpeg), config)
+        }
+        Some(jpeg) => transcode_jxl(&jpeg, config),
+        None => {
+            // Pixel encodes are tagged sRGB, so a preserved profile is
+            // converted into the pixels, as for AVIF.
+            let converted = meta.to_srgb(img);
+            encode_jxl(converted.as_ref().unwrap_or(img), meta, config)
+        }
+    }
+    .map_err(|e| err(ErrorKind::Encode, &e))?;
+
+    let size = data.len() as u64;
+    if !is_worth_keeping(size, original_size, config) {
+        return Ok(Encoded::new(0, SizeOutcome::KeptOriginal));
+    }
+
+    if !config.dry_run {
+        write_verified(&jxl_path, &data, ErrorStage::Jxl, is_jxl)?;
+    }
+    let encoded = Encoded::new(size, SizeOutcome::Optimized);
+    Ok(if lossless {
+        encoded.lossless()
+    } else {
+        encoded
+    })
+}
+
+// EXIF needs the container format; bare codestreams are smaller otherwise.
+fn encode_jxl(
+    img: &DynamicImage,
+    meta: &Metadata,
+    config: &OptimizeConfig,
+) -> Result<Vec<u8>, EncodeError> {
+    let has_alpha = img.color().has_alpha();
+    let lossless = config.jxl_quality >= 100.0;
+
+    let mut encoder = encoder_builder()
+        .has_alpha(has_alpha)
+        .lossless(lossless)
+        .uses_original_profile(lossless)
+        .use_container(meta.exif().is_some() || meta.xmp().is_some())
+        .quality(jxl_distance(config.jxl_quality))
+        .speed(jxl_speed(config.jxl_effort))
+        .build()?;
+    if let Some(exif) = meta.exif() {
+        // The Exif box opens with the offset of the TIFF header, which follows directly.
+        let mut data = vec![0; 4];
+        data.extend_from_slice(exif);
+        encoder.add_metadata(&JxlMetadata::Exif(&data), true)?;
+    }
+    if let Some(xmp) = meta.xmp() {
+        encoder.add_metadata(&JxlMetadata::Xmp(xmp), true)?;
+    }
+
     let (width, height) = img.dimensions();
+    let result: EncoderResult<u8> = if has_alpha {
+        encoder.encode::<u8, u8>(img.to_rgba8().as_raw(), width, height)?
+    } else {
+        encoder.encode::<u8, u8>(img.to_rgb8().as_raw(), width, height)?
+    };
+    Ok(result.data)
+}
 
-    let src_img = imgref::Img::new(rgba.as_raw().as_rgba(), width as usize, height as usize);
+// The container keeps the JPEG reconstruction box alongside the codestream.
+fn transcode_jxl(jpeg: &[u8], config: &OptimizeConfig) -> Result<Vec<u8>, EncodeError> {
+    let mut encoder = encoder_builder()
+        .use_container(true)
+        .uses_original_profile(true)
+        .speed(jxl_speed(config.jxl_effort))
+        .build()?;
+    Ok(encoder.encode_jpeg(jpeg)?.data)
+}
 
-    let enc = ravif::Encoder::new()
-        .with_quality(65.0)
-        .with_speed(4)
-        .with_alpha_quality(70.0)
-        .encode_rgba(src_img);
+// Same mapping as `cjxl -q`: 100 is lossless, 90 is distance 1.0.
+fn jxl_distance(quality: f32) -> f32 {
+    let q = quality.clamp(0.0, 100.0);
+    if q >= 100.0 {
+        0.0
+    } else if q >= 30.0 {
+        0.1 + (100.0 - q) * 0.09
+    } else {
+        6.4 + 2.5f32.powf((30.0 - q) / 5.0) / 6.25
+    }
+}
+
+fn jxl_speed(effort: u8) -> EncoderSpeed {
+    match effort {
+        0 | 1 => EncoderSpeed::Lightning,
+        2 => EncoderSpeed::Thunder,
+        3 => EncoderSpeed::Falcon,
+        4 => EncoderSpeed::Cheetah,
+        5 => EncoderSpeed::Hare,
+        6 => EncoderSpeed::Wombat,
+        7 => EncoderSpeed::Squirrel,
+        8 => EncoderSpeed::Kitten,
+        _ => EncoderSpeed::Tortoise,
+    }
+}
 
-    match enc {
-        Ok(encoded_image) => {
-            if fs::write(&avif_path, &encoded_image.avif_file).is_ok() {
-                encoded_image.avif_file.len() as u64
+fn is_jpeg(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))
+}
+
+// Binary search for the lowest quality whose decoded output stays within
+// `max_dssim` of the source. When even the top of the range misses the
+// target, that encode is returned along with its score.
+fn search_quality(
+    img: &DynamicImage,
+    max_dssim: f64,
+    format: ImageFormat,
+    stage: ErrorStage,
+    encode: impl Fn(&DynamicImage, f32) -> Result<Vec<u8>, FileError>,
+) -> Result<(Vec<u8>, TargetQuality), FileError> {
+    let err = |kind, e: &dyn Display| FileError::new(stage, kind, e);
+
+    let attr = dssim_core::Dssim::new();
+    let to_dssim = |rgba: &RgbaImage| {
+        let (width, height) = rgba.dimensions();
+        attr.create_image_rgba(rgba.as_raw().as_rgba(), width as usize, height as usize)
+            .ok_or_else(|| err(ErrorKind::Verify, &"image is too small to compare"))
+    };
+    let reference = to_dssim(&img.to_rgba8())?;
+
+    let (mut lo, mut hi) = (*TARGET_QUALITY_RANGE.start(), *TARGET_QUALITY_RANGE.end());
+    let mut best = None;
+    let mut last = None;
+
+    while lo <= hi {
+        let quality = lo + (hi - lo) / 2;
+        let data = encode(img, f32::from(quality))?;
+        let decoded = match format {
+            ImageFormat::Avif => decode_avif(&data),
+            _ => image::load_from_memory_with_format(&data, format)
+                .map(|img| img.to_rgba8())
+                .map_err(|e| e.to_string()),
+        }
+        .map_err(|e| err(ErrorKind::Verify, &e))?;
+        let (dssim, _) = attr.compare(&reference, to_dssim(&decoded)?);
+
+        let candidate = (
+            data,
+            TargetQuality {
+                quality: f32::from(quality),
+                dssim: f64::from(dssim),
+            },
+        );
+        if candidate.1.dssim <= max_dssim {
+            best = Some(candidate);
+            hi = quality - 1;
+        } else {
+            last = Some(candidate);
+            lo = quality + 1;
+        }
+    }
+
+    best.or(last)
+        .ok_or_else(|| err(ErrorKind::Encode, &"empty quality range"))
+}
+
+struct Fitted {
+    data: Vec<u8>,
+    target: Option<TargetQuality>,
+    over_budget: bool,
+    // Size of the encoded image when it had to be downscaled.
+    dimensions: Option<(u32, u32)>,
+}
+
+// Shrinks `encoded` below `budget` bytes by searching the highest quality
+// between `budget_min_quality` and `max_quality`, then, if allowed, by
+// downscaling the image. Returns the smallest attempt, flagged over budget when
+// it still doesn't fit. The perceptual target no longer applies once this
+// kicks in.
+fn fit_budget(
+    img: &DynamicImage,
+    encoded: (Vec<u8>, Option<TargetQuality>),
+    budget: Option<u64>,
+    max_quality: f32,
+    config: &OptimizeConfig,
+    encode: impl Fn(&DynamicImage, f32) -> Result<Vec<u8>, FileError>,
+) -> Result<Fitted, FileError> {
+    let (data, target) = encoded;
+    let fitted = |data, target, over_budget, scaled: Option<&DynamicImage>| Fitted {
+        data,
+        target,
+        over_budget,
+        dimensions: scaled.map(|img| img.dimensions()),
+    };
+    let Some(budget) = budget else {
+        return Ok(fitted(data, target, false, None));
+    };
+    if data.len() as u64 <= budget {
+        return Ok(fitted(data, target, false, None));
+    }
+
+    let max_quality = max_quality.clamp(1.0, 100.0) as u8;
+    let max_quality = target.map_or(max_quality, |t| (t.quality as u8).min(max_quality));
+    let min_quality = config.budget_min_quality.clamp(1, max_quality);
+
+    let mut scaled: Option<DynamicImage> = None;
+    loop {
+        let current = scaled.as_ref().unwrap_or(img);
+
+        let (mut lo, mut hi) = (min_quality, max_quality);
+        let mut best = None;
+        let mut smallest = None;
+        while lo <= hi {
+            let quality = lo + (hi - lo) / 2;
+            let data = encode(current, f32::from(quality))?;
+            if data.len() as u64 <= budget {
+                best = Some(data);
+                lo = quality + 1;
             } else {
-                0
+                smallest = Some(data);
+                if quality == min_quality {
+                    break;
+                }
+                hi = quality - 1;
             }
         }
-        Err(e) => {
-            eprintln!("AVIF Error for {:?}: {}", path, e);
-            0
+        if let Some(best) = best {
+            return Ok(fitted(best, None, false, scaled.as_ref()));
         }
+        let Some(smallest) = smallest else {
+            return Ok(fitted(data, target, true, None));
+        };
+
+        // Bytes grow roughly with pixel area, so aim a little under the budget.
+        let (width, height) = current.dimensions();
+        let ratio = ((budget as f64 / smallest.len() as f64).sqrt() * 0.95).clamp(0.5, 0.95);
+        let (new_width, new_height) = (
+            (f64::from(width) * ratio) as u32,
+            (f64::from(height) * ratio) as u32,
+        );
+        if !config.budget_downscale || new_width.min(new_height) < BUDGET_MIN_DIMENSION {
+            return Ok(fitted(smallest, None, true, scaled.as_ref()));
+        }
+        scaled = Some(current.resize_exact(new_width, new_height, FilterType::Lanczos3));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_gif(global: &[u8], frames: &[gif::Frame]) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut out, 4, 2, global).unwrap();
+            for frame in frames {
+                encoder.write_frame(frame).unwrap();
+            }
+        }
+        out
+    }
+
+    fn decode_frames(data: &[u8], output: gif::ColorOutput) -> Vec<gif::Frame<'static>> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(output);
+        let mut decoder = options.read_info(data).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn optimize_gif_compacts_palettes_without_changing_pixels() {
+        // Of eight global entries only 1, 5 and 7 are drawn and 6 is transparent.
+        let global: Vec<u8> = (0..8u8).flat_map(|i| [i * 30, 255 - i * 30, i]).collect();
+        let first = gif::Frame {
+            width: 4,
+            height: 2,
+            buffer: vec![1, 5, 5, 7, 6, 1, 7, 6].into(),
+            transparent: Some(6),
+            ..Default::default()
+        };
+        // A local palette where index 0 is transparent and never drawn.
+        let second = gif::Frame {
+            width: 4,
+            height: 2,
+            buffer: vec![2, 4, 4, 2, 4, 2, 2, 4].into(),
+            transparent: Some(0),
+            palette: Some((0..6u8).flat_map(|i| [i, i * 40, 200]).collect()),
+            ..Default::default()
+        };
+        let original = encode_gif(&global, &[first, second]);
+
+        let optimized = optimize_gif(&original).unwrap();
+
+        let before = decode_frames(&original, gif::ColorOutput::RGBA);
+        let after = decode_frames(&optimized, gif::ColorOutput::RGBA);
+        assert_eq!(before.len(), after.len());
+        for (before, after) in before.iter().zip(&after) {
+            assert_eq!(before.buffer, after.buffer);
+        }
+
+        let indexed = decode_frames(&optimized, gif::ColorOutput::Indexed);
+        // Global entries 1, 5, 6 and 7 become 0 through 3.
+        assert_eq!(indexed[0].buffer.as_ref(), &[0, 1, 1, 3, 2, 0, 3, 2]);
+        assert_eq!(indexed[0].transparent, Some(2));
+        // Local entries 0, 2 and 4 become 0 through 2.
+        assert_eq!(indexed[1].buffer.as_ref(), &[1, 2, 2, 1, 2, 1, 1, 2]);
+        assert_eq!(indexed[1].transparent, Some(0));
+
+        let options = gif::DecodeOptions::new();
+        let decoder = options.read_info(optimized.as_slice()).unwrap();
+        assert_eq!(decoder.global_palette().unwrap().len(), 4 * 3);
+    }
+
+    fn minify(svg: &str, precision: u8) -> String {
+        String::from_utf8(minify_svg(svg.as_bytes(), precision).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn variant_specs_drop_densities_that_overflow() {
+        let config = OptimizeConfig {
+            no_upscale: false,
+            variant_densities: vec![1, 2, u32::MAX],
+            variant_base_width: Some(u32::MAX / 2 + 1),
+            ..OptimizeConfig::default()
+        };
+        let widths: Vec<u32> = variant_specs(800, &config)
+            .iter()
+            .map(|spec| spec.width)
+            .collect();
+        assert_eq!(widths, [u32::MAX / 2 + 1]);
+    }
+
+    #[test]
+    fn minify_svg_keeps_styling_hooks_and_drops_editor_data() {
+        let svg = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Generator: test -->
+<svg xmlns="http://www.w3.org/2000/svg"
+     xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape"
+     viewBox="0 0 24 24" class="icon" inkscape:version="1.3">
+  <metadata><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"/></metadata>
+  <style>.icon > path { fill: currentColor; }</style>
+  <g id="layer" inkscape:label="Layer 1">
+    <path d="M 1.23456 2.5 L 10.000 0.500" style="stroke: currentColor"/>
+  </g>
+</svg>
+"#;
+        assert_eq!(
+            minify(svg, 2),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 24 24\" class=\"icon\">\
+             <style>.icon > path { fill: currentColor; }</style>\
+             <g id=\"layer\"><path d=\"M 1.23 2.5 L 10 .5\" style=\"stroke: currentColor\"/></g>\
+             </svg>"
+        );
+    }
+
+    #[test]
+    fn minify_svg_collapses_only_groups_that_are_safe_to_drop() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24">
+  <g><g fill="red"><path d="M0 0h1"/></g></g>
+  <g transform="translate(1 2)"><path d="M0 0h1" transform="scale(2)"/></g>
+  <g opacity=".5"><rect width="1" height="1"/><rect x="2" width="1" height="1"/></g>
+  <g id="shape" fill="blue"><circle r="1"/></g>
+</svg>"#;
+        assert_eq!(
+            minify(svg, 3),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 24 24\">\
+             <path fill=\"red\" d=\"M0 0h1\"/>\
+             <g transform=\"translate(1 2)\"><path d=\"M0 0h1\" transform=\"scale(2)\"/></g>\
+             <g opacity=\".5\"><rect width=\"1\" height=\"1\"/><rect x=\"2\" width=\"1\" height=\"1\"/></g>\
+             <g id=\"shape\" fill=\"blue\"><circle r=\"1\"/></g></svg>"
+        );
+
+        // A stylesheet may select on the tree, so even an empty group stays.
+        let styled = r#"<svg xmlns="http://www.w3.org/2000/svg"><style>g > path { fill: red; }</style><g><path d="M0 0h1"/></g></svg>"#;
+        assert_eq!(minify(styled, 3), styled);
+    }
+
+    #[test]
+    fn minify_svg_keeps_text_whitespace() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg">
+  <text x="0" y="10">Hello <tspan>big</tspan> world</text>
+</svg>"#;
+        assert_eq!(
+            minify(svg, 3),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\">\
+             <text x=\"0\" y=\"10\">Hello <tspan>big</tspan> world</text></svg>"
+        );
+    }
+
+    #[test]
+    fn round_svg_numbers_keeps_numbers_apart() {
+        assert_eq!(round_svg_numbers("d", "M1.04.5-0.26", 1), "M1 .5-.3");
+        assert_eq!(round_svg_numbers("points", "1e5,2.0001", 3), "1e5,2");
+        assert_eq!(round_svg_numbers("width", "1.50em", 3), "1.5em");
+        assert_eq!(
+            round_svg_numbers("transform", "rotate(33.3333333)", 1),
+            "rotate(33.33333)"
+        );
+        // Arc flags can run into the next number, so arcs are left alone.
+        assert_eq!(
+            round_svg_numbers("d", "M0 0a5 5 0 011.555 1", 1),
+            "M0 0a5 5 0 011.555 1"
+        );
+        assert_eq!(round_svg_numbers("fill", "0.123456", 1), "0.123456");
     }
 }
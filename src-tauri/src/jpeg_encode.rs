@@ -0,0 +1,145 @@
+use mozjpeg_sys::{
+    boolean, jpeg_compress_struct, JDIMENSION, JPEG_LIB_VERSION, JSAMPROW, J_BOOLEAN_PARAM,
+    J_COLOR_SPACE,
+};
+use std::mem;
+use std::os::raw::c_int;
+use std::slice;
+
+use crate::jpeg_lossless;
+
+pub struct JpegParams {
+    pub quality: f32,
+    // Luma pixels per chroma sample, horizontally and vertically.
+    pub chroma: (u8, u8),
+    pub smoothing: u8,
+    pub progressive: bool,
+    pub trellis: bool,
+}
+
+// Encodes packed 8-bit RGB with mozjpeg's default profile. This goes through
+// the C API because the mozjpeg crate can't switch trellis quantisation off
+// on its own, only by falling back to the libjpeg-turbo profile.
+pub fn encode_rgb(
+    rgb: &[u8],
+    width: u32,
+    height: u32,
+    params: &JpegParams,
+) -> Result<Vec<u8>, String> {
+    let row_len = width as usize * 3;
+    if width == 0 || height == 0 || rgb.len() != row_len * height as usize {
+        return Err("pixel buffer doesn't match the image size".to_string());
+    }
+
+    unsafe {
+        let mut cinfo: jpeg_compress_struct = mem::zeroed();
+        jpeg_lossless::run_to_memory(
+            &mut cinfo,
+            |cinfo, err, out_buf, out_size| {
+                cinfo.common.err = err;
+                mozjpeg_sys::jpeg_CreateCompress(
+                    cinfo,
+                    JPEG_LIB_VERSION,
+                    mem::size_of::<jpeg_compress_struct>(),
+                );
+
+                cinfo.image_width = width as JDIMENSION;
+                cinfo.image_height = height as JDIMENSION;
+                cinfo.input_components = 3;
+                cinfo.in_color_space = J_COLOR_SPACE::JCS_RGB;
+                // Resets every parameter, so it goes first.
+                mozjpeg_sys::jpeg_set_defaults(cinfo);
+
+                for param in [
+                    J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT,
+                    J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC,
+                ] {
+                    mozjpeg_sys::jpeg_c_set_bool_param(cinfo, param, boolean::from(params.trellis));
+                }
+                mozjpeg_sys::jpeg_set_quality(cinfo, params.quality as c_int, boolean::from(false));
+
+                let components =
+                    slice::from_raw_parts_mut(cinfo.comp_info, cinfo.num_components as usize);
+                for (i, component) in components.iter_mut().enumerate() {
+                    let (h, v) = if i == 0 { params.chroma } else { (1, 1) };
+                    component.h_samp_factor = c_int::from(h);
+                    component.v_samp_factor = c_int::from(v);
+                }
+                cinfo.smoothing_factor = c_int::from(params.smoothing);
+                cinfo.optimize_coding = boolean::from(true);
+                jpeg_lossless::set_progressive(cinfo, params.progressive);
+
+                mozjpeg_sys::jpeg_mem_dest(cinfo, out_buf, out_size);
+                mozjpeg_sys::jpeg_start_compress(cinfo, boolean::from(true));
+                let rows: Vec<JSAMPROW> =
+                    rgb.chunks_exact(row_len).map(|row| row.as_ptr()).collect();
+                let mut written = 0;
+                while written < rows.len() {
+                    let count = mozjpeg_sys::jpeg_write_scanlines(
+                        cinfo,
+                        rows[written..].as_ptr(),
+                        (rows.len() - written) as JDIMENSION,
+                    );
+                    if count == 0 {
+                        break;
+                    }
+                    written += count as usize;
+                }
+                mozjpeg_sys::jpeg_finish_compress(cinfo);
+            },
+            |cinfo| mozjpeg_sys::jpeg_destroy_compress(cinfo),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the SOF marker and each component's sampling byte.
+    fn frame_header(jpeg: &[u8]) -> (u8, Vec<u8>) {
+        let mut pos = 2;
+        while pos + 4 <= jpeg.len() {
+            assert_eq!(jpeg[pos], 0xFF);
+            let marker = jpeg[pos + 1];
+            let len = usize::from(u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]));
+            if matches!(marker, 0xC0..=0xC2) {
+                let segment = &jpeg[pos + 4..pos + 2 + len];
+                let count = usize::from(segment[5]);
+                let sampling = (0..count).map(|i| segment[6 + i * 3 + 1]).collect();
+                return (marker, sampling);
+            }
+            pos += 2 + len;
+        }
+        panic!("no frame header");
+    }
+
+    #[test]
+    fn every_scan_and_trellis_combination_decodes() {
+        let (width, height) = (40, 24);
+        let rgb: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i * 7) as u8, (i * 3) as u8, (i / 5) as u8])
+            .collect();
+        for progressive in [false, true] {
+            for trellis in [false, true] {
+                for chroma in [(1, 1), (2, 2)] {
+                    let params = JpegParams {
+                        quality: 80.0,
+                        chroma,
+                        smoothing: 0,
+                        progressive,
+                        trellis,
+                    };
+                    let jpeg = encode_rgb(&rgb, width, height, &params).unwrap();
+                    let decoded = image::load_from_memory(&jpeg).unwrap();
+                    assert_eq!((decoded.width(), decoded.height()), (width, height));
+
+                    let (sof, sampling) = frame_header(&jpeg);
+                    assert_eq!(sof, if progressive { 0xC2 } else { 0xC0 });
+                    let luma = (chroma.0 << 4) | chroma.1;
+                    assert_eq!(sampling, [luma, 0x11, 0x11]);
+                }
+            }
+        }
+    }
+}
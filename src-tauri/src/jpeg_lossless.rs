@@ -0,0 +1,116 @@
+use mozjpeg_sys::{
+    boolean, jpeg_common_struct, jpeg_compress_struct, jpeg_decompress_struct, jpeg_error_mgr,
+    JPEG_LIB_VERSION, J_BOOLEAN_PARAM,
+};
+use std::mem;
+use std::os::raw::{c_int, c_ulong};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+// Rewrites a JPEG from its DCT coefficients, the way `jpegtran -copy none
+// -optimize [-progressive]` does: Huffman tables are rebuilt and the scan
+// layout changes, but the decoded pixels stay bit-identical. No markers are
+// copied; the caller re-attaches whatever metadata it keeps.
+pub fn transcode(data: &[u8], progressive: bool) -> Result<Vec<u8>, String> {
+    unsafe {
+        let mut structs: (jpeg_decompress_struct, jpeg_compress_struct) =
+            (mem::zeroed(), mem::zeroed());
+        run_to_memory(
+            &mut structs,
+            |(src, dst), err, out_buf, out_size| {
+                src.common.err = err;
+                dst.common.err = err;
+                mozjpeg_sys::jpeg_CreateDecompress(
+                    src,
+                    JPEG_LIB_VERSION,
+                    mem::size_of::<jpeg_decompress_struct>(),
+                );
+                mozjpeg_sys::jpeg_CreateCompress(
+                    dst,
+                    JPEG_LIB_VERSION,
+                    mem::size_of::<jpeg_compress_struct>(),
+                );
+
+                mozjpeg_sys::jpeg_mem_src(src, data.as_ptr(), data.len() as c_ulong);
+                mozjpeg_sys::jpeg_read_header(src, boolean::from(true));
+                let coefficients = mozjpeg_sys::jpeg_read_coefficients(src);
+
+                mozjpeg_sys::jpeg_copy_critical_parameters(src, dst);
+                dst.optimize_coding = boolean::from(true);
+                set_progressive(dst, progressive);
+
+                mozjpeg_sys::jpeg_mem_dest(dst, out_buf, out_size);
+                mozjpeg_sys::jpeg_write_coefficients(dst, coefficients);
+                mozjpeg_sys::jpeg_finish_compress(dst);
+                mozjpeg_sys::jpeg_finish_decompress(src);
+            },
+            |(src, dst)| {
+                mozjpeg_sys::jpeg_destroy_compress(dst);
+                mozjpeg_sys::jpeg_destroy_decompress(src);
+            },
+        )
+    }
+}
+
+// Runs a libjpeg job that compresses into memory. `job` attaches the error
+// manager to its structs before creating them and hands the buffer to
+// `jpeg_mem_dest`. libjpeg reports errors by unwinding out of `error_exit`,
+// so `destroy` runs whether or not the job finished.
+pub(crate) unsafe fn run_to_memory<S>(
+    structs: &mut S,
+    job: impl FnOnce(&mut S, *mut jpeg_error_mgr, &mut *mut u8, &mut c_ulong),
+    destroy: impl FnOnce(&mut S),
+) -> Result<Vec<u8>, String> {
+    let mut err: jpeg_error_mgr = mem::zeroed();
+    mozjpeg_sys::jpeg_std_error(&mut err);
+    err.error_exit = Some(unwind_error_exit);
+    err.emit_message = Some(silence_message);
+
+    let mut out_buf: *mut u8 = ptr::null_mut();
+    let mut out_size: c_ulong = 0;
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        job(structs, &mut err, &mut out_buf, &mut out_size)
+    }));
+    destroy(structs);
+
+    let output = match result {
+        Ok(()) if !out_buf.is_null() => {
+            Ok(slice::from_raw_parts(out_buf, out_size as usize).to_vec())
+        }
+        Ok(()) => Err("libjpeg produced no output".to_string()),
+        Err(payload) => Err(payload
+            .downcast::<String>()
+            .map(|msg| *msg)
+            .unwrap_or_else(|_| "libjpeg fatal error".to_string())),
+    };
+    if !out_buf.is_null() {
+        libc::free(out_buf.cast());
+    }
+    output
+}
+
+// Without a scan script mozjpeg writes a single baseline scan.
+pub(crate) unsafe fn set_progressive(cinfo: &mut jpeg_compress_struct, progressive: bool) {
+    if progressive {
+        mozjpeg_sys::jpeg_simple_progression(cinfo);
+    } else {
+        mozjpeg_sys::jpeg_c_set_bool_param(
+            cinfo,
+            J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS,
+            boolean::from(false),
+        );
+        cinfo.scan_info = ptr::null();
+        cinfo.num_scans = 0;
+    }
+}
+
+#[cold]
+extern "C-unwind" fn unwind_error_exit(cinfo: &mut jpeg_common_struct) {
+    let code = unsafe { cinfo.err.as_ref().map_or(0, |err| err.msg_code) };
+    // resume_unwind skips the panic hook, so a corrupt file doesn't print a backtrace.
+    panic::resume_unwind(Box::new(format!("libjpeg fatal error: code {}", code)));
+}
+
+extern "C-unwind" fn silence_message(_cinfo: &mut jpeg_common_struct, _level: c_int) {}
@@ -1,3 +1,19 @@
+pub mod assets;
+pub mod backup;
+#[cfg(feature = "gui")]
+pub mod commands;
+pub mod image_ops;
+pub mod jpeg_encode;
+pub mod jpeg_lossless;
+pub mod manifest;
+pub mod metadata;
+pub mod optimizer;
+pub mod report;
+pub mod reporter;
+pub mod types;
+pub mod webp_anim;
+
+#[cfg(feature = "gui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -1,22 +1,16 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod commands;
-mod image_ops;
-mod optimizer;
-mod tools;
-mod types;
-
 use moka::future::Cache;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use commands::{
-    cancel_optimization, generate_thumbnail, get_last_result, get_processing_state,
-    run_optimization, scan_dropped_paths
+use app_lib::commands::{
+    cancel_optimization, export_report, generate_thumbnail, get_last_result, get_processing_state,
+    run_optimization, scan_dropped_paths, undo_last_run
 };
-use image_ops::ImageCache;
-use types::AppState;
+use app_lib::image_ops::ImageCache;
+use app_lib::types::AppState;
 
 fn main() {
     let cache = Cache::builder()
@@ -41,7 +35,9 @@ fn main() {
             generate_thumbnail,
             get_processing_state,
             get_last_result,
-            scan_dropped_paths
+            scan_dropped_paths,
+            export_report,
+            undo_last_run
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
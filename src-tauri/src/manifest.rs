@@ -0,0 +1,353 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::image_ops::write_verified;
+use crate::types::{
+    AvifColorModel, ChromaSubsampling, ErrorStage, IccPolicy, MetadataPolicy, OptimizeConfig,
+    ResizeFilter, ResizeFit, SvgRasterFormat, WebpLossless,
+};
+
+pub const MANIFEST_FILE: &str = ".images-optimizer.json";
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub source_hash: String,
+    pub output_hash: String,
+    pub settings: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub entries: HashMap<String, ManifestEntry>,
+}
+
+struct Pending {
+    dir: PathBuf,
+    key: String,
+    source_hash: String,
+}
+
+pub struct ManifestStore {
+    settings: String,
+    manifests: HashMap<PathBuf, Manifest>,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+impl ManifestStore {
+    pub fn new(config: &OptimizeConfig) -> Self {
+        Self {
+            settings: settings_fingerprint(config),
+            manifests: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn is_up_to_date(&mut self, src: &Path, dest: &Path, root: &Path) -> bool {
+        let Ok(source_hash) = hash_file(src) else {
+            return false;
+        };
+
+        let dir = manifest_dir(root);
+        let key = src
+            .strip_prefix(&dir)
+            .unwrap_or(src)
+            .to_string_lossy()
+            .replace('\\', "/");
+
+        let manifest = self
+            .manifests
+            .entry(dir.clone())
+            .or_insert_with(|| load_manifest(&dir));
+
+        // In replace mode only the written output counts: an original put
+        // back by undo still hashes to `source_hash` and has to run again.
+        let up_to_date = manifest.entries.get(&key).is_some_and(|e| {
+            e.settings == self.settings
+                && if src == dest {
+                    e.output_hash == source_hash
+                } else {
+                    e.source_hash == source_hash && dest.exists()
+                }
+        });
+
+        if !up_to_date {
+            self.pending.insert(
+                src.to_path_buf(),
+                Pending {
+                    dir,
+                    key,
+                    source_hash,
+                },
+            );
+        }
+
+        up_to_date
+    }
+
+    pub fn record(&mut self, src: &Path) {
+        let Some(pending) = self.pending.remove(src) else {
+            return;
+        };
+        let Ok(output_hash) = hash_file(src) else {
+            return;
+        };
+
+        if let Some(manifest) = self.manifests.get_mut(&pending.dir) {
+            manifest.entries.insert(
+                pending.key,
+                ManifestEntry {
+                    source_hash: pending.source_hash,
+                    output_hash,
+                    settings: self.settings.clone(),
+                },
+            );
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        for (dir, manifest) in &self.manifests {
+            if manifest.entries.is_empty() {
+                continue;
+            }
+            let data = serde_json::to_vec_pretty(manifest).map_err(|e| e.to_string())?;
+            write_verified(
+                &dir.join(MANIFEST_FILE),
+                &data,
+                ErrorStage::Prepare,
+                |written| serde_json::from_slice::<Manifest>(written).is_ok(),
+            )
+            .map_err(|e| format!("Failed to write manifest in {}: {}", dir.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+fn manifest_dir(root: &Path) -> PathBuf {
+    if root.is_dir() {
+        root.to_path_buf()
+    } else {
+        root.parent().unwrap_or(Path::new(".")).to_path_buf()
+    }
+}
+
+fn load_manifest(dir: &Path) -> Manifest {
+    fs::read(dir.join(MANIFEST_FILE))
+        .ok()
+        .and_then(|data| serde_json::from_slice::<Manifest>(&data).ok())
+        .filter(|m| m.version == MANIFEST_VERSION)
+        .unwrap_or(Manifest {
+            version: MANIFEST_VERSION,
+            entries: HashMap::new(),
+        })
+}
+
+// Everything that changes the bytes written. Where outputs go, reporting,
+// dry runs and backups don't, so changing them keeps the entries valid.
+#[derive(Serialize)]
+struct OutputSettings<'a> {
+    jpg_q: &'a u8,
+    png_min: &'a u8,
+    png_max: &'a u8,
+    jpg_lossless: &'a bool,
+    jpg_subsampling: &'a ChromaSubsampling,
+    jpg_progressive: &'a bool,
+    jpg_trellis: &'a bool,
+    jpg_smoothing: &'a u8,
+    webp: &'a bool,
+    avif: &'a bool,
+    webp_quality: &'a f32,
+    webp_lossless: &'a WebpLossless,
+    webp_near_lossless: &'a u8,
+    webp_alpha_quality: &'a u8,
+    webp_method: &'a u8,
+    avif_quality: &'a f32,
+    avif_alpha_quality: &'a f32,
+    avif_speed: &'a u8,
+    avif_bit_depth: &'a u8,
+    avif_color_model: &'a AvifColorModel,
+    jxl: &'a bool,
+    jxl_quality: &'a f32,
+    jxl_effort: &'a u8,
+    jxl_transcode_jpeg: &'a bool,
+    jxl_strip_metadata: &'a bool,
+    target_dssim: &'a Option<f64>,
+    jpg_max_bytes: &'a Option<u64>,
+    webp_max_bytes: &'a Option<u64>,
+    avif_max_bytes: &'a Option<u64>,
+    budget_min_quality: &'a u8,
+    budget_downscale: &'a bool,
+    max_width: &'a Option<u32>,
+    max_height: &'a Option<u32>,
+    resize_fit: &'a ResizeFit,
+    resize_filter: &'a ResizeFilter,
+    no_upscale: &'a bool,
+    variant_widths: &'a Vec<u32>,
+    variant_densities: &'a Vec<u32>,
+    variant_base_width: &'a Option<u32>,
+    metadata: &'a MetadataPolicy,
+    icc: &'a Option<IccPolicy>,
+    svg_precision: &'a u8,
+    svg_raster_widths: &'a Vec<u32>,
+    svg_raster_format: &'a SvgRasterFormat,
+    optimize_original: &'a bool,
+    min_saving_percent: &'a f32,
+}
+
+fn settings_fingerprint(config: &OptimizeConfig) -> String {
+    // No `..`: a new config field has to be sorted into one side or the other.
+    let OptimizeConfig {
+        tasks: _,
+        jpg_q,
+        png_min,
+        png_max,
+        jpg_lossless,
+        jpg_subsampling,
+        jpg_progressive,
+        jpg_trellis,
+        jpg_smoothing,
+        webp,
+        avif,
+        webp_quality,
+        webp_lossless,
+        webp_near_lossless,
+        webp_alpha_quality,
+        webp_method,
+        avif_quality,
+        avif_alpha_quality,
+        avif_speed,
+        avif_bit_depth,
+        avif_color_model,
+        jxl,
+        jxl_quality,
+        jxl_effort,
+        jxl_transcode_jpeg,
+        jxl_strip_metadata,
+        target_dssim,
+        jpg_max_bytes,
+        webp_max_bytes,
+        avif_max_bytes,
+        budget_min_quality,
+        budget_downscale,
+        max_width,
+        max_height,
+        resize_fit,
+        resize_filter,
+        no_upscale,
+        variant_widths,
+        variant_densities,
+        variant_base_width,
+        asset_manifest: _,
+        picture_snippets: _,
+        metadata,
+        icc,
+        svg_precision,
+        svg_raster_widths,
+        svg_raster_format,
+        optimize_original,
+        replace: _,
+        output_dir: _,
+        min_saving_percent,
+        use_manifest: _,
+        dry_run: _,
+        dry_run_sample: _,
+        backup: _,
+        backup_dir: _,
+        backup_max_age_days: _,
+        backup_max_size_mb: _,
+    } = config;
+
+    let settings = OutputSettings {
+        jpg_q,
+        png_min,
+        png_max,
+        jpg_lossless,
+        jpg_subsampling,
+        jpg_progressive,
+        jpg_trellis,
+        jpg_smoothing,
+        webp,
+        avif,
+        webp_quality,
+        webp_lossless,
+        webp_near_lossless,
+        webp_alpha_quality,
+        webp_method,
+        avif_quality,
+        avif_alpha_quality,
+        avif_speed,
+        avif_bit_depth,
+        avif_color_model,
+        jxl,
+        jxl_quality,
+        jxl_effort,
+        jxl_transcode_jpeg,
+        jxl_strip_metadata,
+        target_dssim,
+        jpg_max_bytes,
+        webp_max_bytes,
+        avif_max_bytes,
+        budget_min_quality,
+        budget_downscale,
+        max_width,
+        max_height,
+        resize_fit,
+        resize_filter,
+        no_upscale,
+        variant_widths,
+        variant_densities,
+        variant_base_width,
+        metadata,
+        icc,
+        svg_precision,
+        svg_raster_widths,
+        svg_raster_format,
+        optimize_original,
+        min_saving_percent,
+    };
+
+    let json = serde_json::to_string(&settings).unwrap_or_default();
+    to_hex(&Sha256::digest(json.as_bytes()))
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let data = fs::read(path)?;
+    Ok(to_hex(&Sha256::digest(&data)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restored_original_is_not_up_to_date_in_replace_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        let config = OptimizeConfig {
+            replace: true,
+            ..Default::default()
+        };
+
+        fs::write(&src, b"original").unwrap();
+        let mut store = ManifestStore::new(&config);
+        assert!(!store.is_up_to_date(&src, &src, dir.path()));
+        fs::write(&src, b"optimized").unwrap();
+        store.record(&src);
+        store.save().unwrap();
+
+        let mut store = ManifestStore::new(&config);
+        assert!(store.is_up_to_date(&src, &src, dir.path()));
+
+        fs::write(&src, b"original").unwrap();
+        let mut store = ManifestStore::new(&config);
+        assert!(!store.is_up_to_date(&src, &src, dir.path()));
+    }
+}
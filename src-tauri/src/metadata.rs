@@ -0,0 +1,607 @@
+use exif::experimental::Writer;
+use exif::{Field, In, Tag};
+use image::DynamicImage;
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::png::{Png, PngChunk};
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::{self, WebP};
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+use lcms2::{ColorSpaceSignature, Intent, PixelFormat, Profile, Transform};
+use std::io::Cursor;
+
+use crate::types::MetadataPolicy;
+
+const ORIENTATION_TAG: u16 = 0x0112;
+const COPYRIGHT_TAGS: &[Tag] = &[Tag::Copyright, Tag::Artist];
+const JPEG_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_CHUNK_ITXT: [u8; 4] = *b"iTXt";
+
+// What survives from the source file. `orientation` is always read so the
+// pixels can be rotated, even when the EXIF block itself is dropped. The ICC
+// profile is always read too: whether it is embedded or converted into the
+// pixels is up to the effective `IccPolicy`.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub orientation: u32,
+    exif: Option<Bytes>,
+    icc: Option<Bytes>,
+    xmp: Option<Bytes>,
+    converted: bool,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            orientation: 1,
+            exif: None,
+            icc: None,
+            xmp: None,
+            converted: false,
+        }
+    }
+}
+
+impl Metadata {
+    pub fn read(data: &[u8], policy: MetadataPolicy) -> Self {
+        let Ok(Some(image)) = DynImage::from_bytes(Bytes::copy_from_slice(data)) else {
+            return Self::default();
+        };
+
+        let exif = image.exif();
+        let orientation = exif.as_deref().and_then(read_orientation).unwrap_or(1);
+
+        let exif = match policy {
+            MetadataPolicy::StripAll => None,
+            MetadataPolicy::KeepCopyright => exif
+                .as_deref()
+                .and_then(|e| rebuild_exif(e, COPYRIGHT_TAGS)),
+            MetadataPolicy::KeepAll => exif.map(reset_orientation),
+        };
+        let xmp = match policy {
+            MetadataPolicy::KeepAll => read_xmp(&image),
+            MetadataPolicy::StripAll | MetadataPolicy::KeepCopyright => None,
+        };
+
+        Self {
+            orientation,
+            exif,
+            icc: image.icc_profile(),
+            xmp,
+            converted: false,
+        }
+    }
+
+    // For coefficient-only rewrites the pixels are never rotated, so the
+    // Orientation tag has to survive every policy. The ICC profile is kept
+    // too, since converting to sRGB would need a re-encode.
+    pub fn read_lossless(data: &[u8], policy: MetadataPolicy) -> Self {
+        let Ok(Some(image)) = DynImage::from_bytes(Bytes::copy_from_slice(data)) else {
+            return Self::default();
+        };
+
+        let exif = image.exif();
+        let exif = match policy {
+            MetadataPolicy::StripAll => exif
+                .as_deref()
+                .and_then(|e| rebuild_exif(e, &[Tag::Orientation])),
+            MetadataPolicy::KeepCopyright => exif
+                .as_deref()
+                .and_then(|e| rebuild_exif(e, &[Tag::Orientation, Tag::Copyright, Tag::Artist])),
+            MetadataPolicy::KeepAll => exif,
+        };
+        let xmp = match policy {
+            MetadataPolicy::KeepAll => read_xmp(&image),
+            MetadataPolicy::StripAll | MetadataPolicy::KeepCopyright => None,
+        };
+
+        Self {
+            orientation: 1,
+            exif,
+            icc: image.icc_profile(),
+            xmp,
+            converted: false,
+        }
+    }
+
+    // Bakes the profile into the pixels and drops it. An unusable profile is
+    // dropped all the same, since it can't be honoured either way.
+    pub fn into_srgb(&mut self, img: DynamicImage) -> DynamicImage {
+        let Some(icc) = self.icc.take() else {
+            return img;
+        };
+        match convert_to_srgb(&img, &icc) {
+            Some(srgb) => {
+                self.converted = true;
+                srgb
+            }
+            None => img,
+        }
+    }
+
+    // Whether the decoded pixels still match the source file byte for byte.
+    pub fn pixels_unchanged(&self) -> bool {
+        self.orientation <= 1 && !self.converted
+    }
+
+    // `None` when there is no profile or it isn't an RGB one lcms2 can use,
+    // in which case the pixels are left as they are.
+    pub fn to_srgb(&self, img: &DynamicImage) -> Option<DynamicImage> {
+        convert_to_srgb(img, self.icc.as_deref()?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc.is_none() && self.xmp.is_none()
+    }
+
+    pub fn exif(&self) -> Option<&[u8]> {
+        self.exif.as_deref()
+    }
+
+    pub fn xmp(&self) -> Option<&[u8]> {
+        self.xmp.as_deref()
+    }
+
+    // A JPEG kept coefficient for coefficient carries every source segment, so
+    // they are all swapped for what the policy keeps. JFIF and Adobe segments
+    // stay, since decoders need them to read the colours.
+    pub fn replace_in_jpeg(&self, data: Vec<u8>) -> Vec<u8> {
+        let bytes = Bytes::from(data);
+        let Ok(mut jpeg) = Jpeg::from_bytes(bytes.clone()) else {
+            return bytes.to_vec();
+        };
+        jpeg.segments_mut().retain(|segment| {
+            !matches!(
+                segment.marker(),
+                markers::APP1..=markers::APP13 | markers::APP15 | markers::COM
+            )
+        });
+        jpeg.set_exif(self.exif.clone());
+        jpeg.set_icc_profile(self.icc.clone());
+        set_jpeg_xmp(&mut jpeg, self.xmp.clone());
+        jpeg.encoder().bytes().to_vec()
+    }
+
+    // Formats img-parts can't edit are returned untouched.
+    pub fn embed(&self, data: Vec<u8>) -> Vec<u8> {
+        if self.is_empty() {
+            return data;
+        }
+
+        let bytes = Bytes::from(data);
+        match DynImage::from_bytes(bytes.clone()) {
+            Ok(Some(mut image)) => {
+                image.set_exif(self.exif.clone());
+                image.set_icc_profile(self.icc.clone());
+                match image {
+                    DynImage::Jpeg(ref mut jpeg) => set_jpeg_xmp(jpeg, self.xmp.clone()),
+                    DynImage::Png(ref mut png) => set_png_xmp(png, self.xmp.clone()),
+                    DynImage::WebP(ref mut webp) => set_webp_xmp(webp, self.xmp.clone()),
+                }
+                image.encoder().bytes().to_vec()
+            }
+            _ => bytes.to_vec(),
+        }
+    }
+}
+
+// img-parts only knows EXIF and ICC, so XMP is found by hand: a JPEG APP1
+// segment behind the XMP namespace, an uncompressed PNG iTXt chunk with the
+// XMP keyword, or a WebP `XMP ` chunk.
+fn read_xmp(image: &DynImage) -> Option<Bytes> {
+    match image {
+        DynImage::Jpeg(jpeg) => jpeg.segments_by_marker(markers::APP1).find_map(|segment| {
+            let contents = segment.contents();
+            contents
+                .starts_with(JPEG_XMP_PREFIX)
+                .then(|| contents.slice(JPEG_XMP_PREFIX.len()..))
+        }),
+        DynImage::Png(png) => png
+            .chunks_by_type(PNG_CHUNK_ITXT)
+            .find_map(|chunk| png_xmp_text(chunk.contents())),
+        DynImage::WebP(webp) => webp.chunk_by_id(webp::CHUNK_XMP)?.content().data().cloned(),
+    }
+}
+
+// iTXt is keyword, NUL, compression flag and method, language tag, NUL,
+// translated keyword, NUL, then the text. XMP is stored uncompressed.
+fn png_xmp_text(contents: &Bytes) -> Option<Bytes> {
+    let rest = contents
+        .strip_prefix(PNG_XMP_KEYWORD)?
+        .strip_prefix(b"\0")?;
+    let (&compressed, rest) = rest.split_first()?;
+    if compressed != 0 {
+        return None;
+    }
+    let rest = rest.get(1..)?;
+    let language_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[language_end + 1..];
+    let keyword_end = rest.iter().position(|&b| b == 0)?;
+    let text = &rest[keyword_end + 1..];
+    let start = contents.len() - text.len();
+    Some(contents.slice(start..))
+}
+
+fn set_jpeg_xmp(jpeg: &mut Jpeg, xmp: Option<Bytes>) {
+    let segments = jpeg.segments_mut();
+    segments.retain(|segment| {
+        segment.marker() != markers::APP1 || !segment.contents().starts_with(JPEG_XMP_PREFIX)
+    });
+    let Some(xmp) = xmp else {
+        return;
+    };
+
+    let mut contents = JPEG_XMP_PREFIX.to_vec();
+    contents.extend_from_slice(&xmp);
+    // Right after the other application segments, where readers look first.
+    let pos = segments
+        .iter()
+        .position(|segment| !(markers::APP0..=markers::APP15).contains(&segment.marker()))
+        .unwrap_or(segments.len());
+    segments.insert(
+        pos,
+        JpegSegment::new_with_contents(markers::APP1, Bytes::from(contents)),
+    );
+}
+
+fn set_png_xmp(png: &mut Png, xmp: Option<Bytes>) {
+    png.chunks_mut()
+        .retain(|chunk| chunk.kind() != PNG_CHUNK_ITXT || png_xmp_text(chunk.contents()).is_none());
+    let Some(xmp) = xmp else {
+        return;
+    };
+
+    let mut contents = PNG_XMP_KEYWORD.to_vec();
+    // Uncompressed, with empty language tag and translated keyword.
+    contents.extend_from_slice(&[0, 0, 0, 0, 0]);
+    contents.extend_from_slice(&xmp);
+    // Ahead of IEND.
+    let pos = png.chunks().len().saturating_sub(1);
+    png.chunks_mut()
+        .insert(pos, PngChunk::new(PNG_CHUNK_ITXT, Bytes::from(contents)));
+}
+
+fn set_webp_xmp(webp: &mut WebP, xmp: Option<Bytes>) {
+    webp.remove_chunks_by_id(webp::CHUNK_XMP);
+    if let Some(xmp) = xmp {
+        webp.chunks_mut()
+            .push(RiffChunk::new(webp::CHUNK_XMP, RiffContent::Data(xmp)));
+    }
+    update_vp8x(webp);
+}
+
+// Metadata chunks are only read from the extended format. img-parts neither
+// adds VP8X for XMP nor keeps its flags current, and drops it once no EXIF or
+// ICC is left even when an ALPH chunk still needs it, so the header and the
+// chunk order are rebuilt from the chunks that are present.
+fn update_vp8x(webp: &mut WebP) {
+    let mut flags = 0;
+    for (id, bit) in [
+        (webp::CHUNK_ICCP, 0x20),
+        (webp::CHUNK_ALPH, 0x10),
+        (webp::CHUNK_EXIF, 0x08),
+        (webp::CHUNK_XMP, 0x04),
+        (webp::CHUNK_ANIM, 0x02),
+    ] {
+        if webp.has_chunk(id) {
+            flags |= bit;
+        }
+    }
+    let Some((width, height)) = webp.dimensions() else {
+        return;
+    };
+    webp.remove_chunks_by_id(webp::CHUNK_VP8X);
+    if flags == 0 {
+        return;
+    }
+    flags |= vp8l_alpha(webp);
+
+    let mut contents = vec![flags, 0, 0, 0];
+    contents.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    contents.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    let chunks = webp.chunks_mut();
+    chunks.insert(
+        0,
+        RiffChunk::new(webp::CHUNK_VP8X, RiffContent::Data(Bytes::from(contents))),
+    );
+    // ICCP right behind the header, EXIF and XMP last; the stable sort keeps
+    // the image chunks in their own order.
+    chunks.sort_by_key(|chunk| match chunk.id() {
+        webp::CHUNK_VP8X => 0,
+        webp::CHUNK_ICCP => 1,
+        webp::CHUNK_EXIF => 3,
+        webp::CHUNK_XMP => 4,
+        _ => 2,
+    });
+}
+
+// The alpha bit a simple lossless WebP carries in its own header.
+fn vp8l_alpha(webp: &WebP) -> u8 {
+    let header = webp
+        .chunk_by_id(webp::CHUNK_VP8L)
+        .and_then(|c| c.content().data()?.get(1..5)?.try_into().ok())
+        .map(u32::from_le_bytes);
+    match header {
+        Some(bits) if bits & (1 << 28) != 0 => 0x10,
+        _ => 0,
+    }
+}
+
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// Conversion runs at 8 bits per channel, so 16-bit sources come out as 8-bit.
+fn convert_to_srgb(img: &DynamicImage, icc: &[u8]) -> Option<DynamicImage> {
+    let profile = Profile::new_icc(icc).ok()?;
+    if profile.color_space() != ColorSpaceSignature::RgbData {
+        return None;
+    }
+    let srgb = Profile::new_srgb();
+
+    if img.color().has_alpha() {
+        let mut rgba = img.to_rgba8();
+        let transform: Transform<u8, u8> = Transform::new(
+            &profile,
+            PixelFormat::RGBA_8,
+            &srgb,
+            PixelFormat::RGBA_8,
+            Intent::Perceptual,
+        )
+        .ok()?;
+        transform.transform_in_place(&mut rgba);
+        Some(DynamicImage::ImageRgba8(rgba))
+    } else {
+        let mut rgb = img.to_rgb8();
+        let transform: Transform<u8, u8> = Transform::new(
+            &profile,
+            PixelFormat::RGB_8,
+            &srgb,
+            PixelFormat::RGB_8,
+            Intent::Perceptual,
+        )
+        .ok()?;
+        transform.transform_in_place(&mut rgb);
+        Some(DynamicImage::ImageRgb8(rgb))
+    }
+}
+
+fn read_orientation(exif: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+    exif.get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+// A fresh EXIF block holding only `tags`, or `None` when the source has none of them.
+fn rebuild_exif(exif: &[u8], tags: &[Tag]) -> Option<Bytes> {
+    let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+    let fields: Vec<&Field> = tags
+        .iter()
+        .copied()
+        .filter_map(|tag| exif.get_field(tag, In::PRIMARY))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut out = Cursor::new(Vec::new());
+    writer.write(&mut out, exif.little_endian()).ok()?;
+    Some(Bytes::from(out.into_inner()))
+}
+
+// The pixels are already rotated, so IFD0's Orientation entry is patched to 1
+// in place rather than re-serialising the whole block.
+fn reset_orientation(exif: Bytes) -> Bytes {
+    let little_endian = match exif.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return exif,
+    };
+    let read_u16 = |buf: &[u8], at: usize| -> Option<u16> {
+        let b: [u8; 2] = buf.get(at..at + 2)?.try_into().ok()?;
+        Some(if little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    };
+    let read_u32 = |buf: &[u8], at: usize| -> Option<u32> {
+        let b: [u8; 4] = buf.get(at..at + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    };
+
+    let mut buf = exif.to_vec();
+    let Some(ifd) = read_u32(&buf, 4).map(|o| o as usize) else {
+        return exif;
+    };
+    let Some(count) = read_u16(&buf, ifd) else {
+        return exif;
+    };
+
+    for i in 0..usize::from(count) {
+        let entry = ifd + 2 + i * 12;
+        if read_u16(&buf, entry) == Some(ORIENTATION_TAG) {
+            let one = if little_endian {
+                1u16.to_le_bytes()
+            } else {
+                1u16.to_be_bytes()
+            };
+            if let Some(value) = buf.get_mut(entry + 8..entry + 10) {
+                value.copy_from_slice(&one);
+            }
+            break;
+        }
+    }
+    Bytes::from(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::Value;
+
+    // A TIFF header and an IFD0 holding Orientation 6 and, optionally, a
+    // Copyright string stored after the IFD.
+    fn exif_blob(little_endian: bool, copyright: Option<&str>) -> Vec<u8> {
+        let u16b = |v: u16| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+        let u32b = |v: u32| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+        let count: u16 = if copyright.is_some() { 2 } else { 1 };
+        let data_offset = 8 + 2 + 12 * u32::from(count) + 4;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(if little_endian { b"II" } else { b"MM" });
+        out.extend_from_slice(&u16b(42));
+        out.extend_from_slice(&u32b(8));
+        out.extend_from_slice(&u16b(count));
+        // A single SHORT sits left-aligned in the value field.
+        out.extend_from_slice(&u16b(ORIENTATION_TAG));
+        out.extend_from_slice(&u16b(3));
+        out.extend_from_slice(&u32b(1));
+        out.extend_from_slice(&u16b(6));
+        out.extend_from_slice(&[0, 0]);
+        if let Some(text) = copyright {
+            out.extend_from_slice(&u16b(0x8298));
+            out.extend_from_slice(&u16b(2));
+            out.extend_from_slice(&u32b(text.len() as u32 + 1));
+            out.extend_from_slice(&u32b(data_offset));
+        }
+        out.extend_from_slice(&u32b(0));
+        if let Some(text) = copyright {
+            out.extend_from_slice(text.as_bytes());
+            out.push(0);
+        }
+        out
+    }
+
+    #[test]
+    fn reset_orientation_patches_only_the_orientation_value() {
+        for little_endian in [true, false] {
+            let blob = exif_blob(little_endian, Some("Jane Doe"));
+            assert_eq!(read_orientation(&blob), Some(6));
+
+            let reset = reset_orientation(Bytes::from(blob.clone()));
+            assert_eq!(read_orientation(&reset), Some(1));
+            assert_eq!(reset.len(), blob.len());
+            let changed = blob
+                .iter()
+                .zip(reset.iter())
+                .filter(|(a, b)| a != b)
+                .count();
+            assert_eq!(changed, 1);
+        }
+    }
+
+    #[test]
+    fn reset_orientation_leaves_unknown_byte_order_alone() {
+        let blob = Bytes::from_static(b"XX\0\x2a\0\0\0\x08");
+        assert_eq!(reset_orientation(blob.clone()), blob);
+    }
+
+    #[test]
+    fn rebuild_exif_keeps_only_the_requested_tags() {
+        for little_endian in [true, false] {
+            let blob = exif_blob(little_endian, Some("Jane Doe"));
+            let rebuilt = rebuild_exif(&blob, COPYRIGHT_TAGS).expect("copyright is present");
+
+            let exif = exif::Reader::new().read_raw(rebuilt.to_vec()).unwrap();
+            assert_eq!(exif.little_endian(), little_endian);
+            assert!(exif.get_field(Tag::Orientation, In::PRIMARY).is_none());
+            let copyright = exif.get_field(Tag::Copyright, In::PRIMARY).unwrap();
+            match copyright.value {
+                Value::Ascii(ref strings) => assert_eq!(strings[0], b"Jane Doe"),
+                ref other => panic!("unexpected copyright value: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rebuild_exif_without_the_requested_tags_is_none() {
+        for little_endian in [true, false] {
+            let blob = exif_blob(little_endian, None);
+            assert!(rebuild_exif(&blob, COPYRIGHT_TAGS).is_none());
+        }
+    }
+
+    fn encoded(format: image::ImageFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut out), format)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn keep_all_carries_xmp_into_every_container() {
+        let xmp = Bytes::from_static(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>");
+        let meta = Metadata {
+            xmp: Some(xmp.clone()),
+            ..Default::default()
+        };
+        let webp = ::webp::Encoder::from_rgb(&[128; 8 * 8 * 3], 8, 8)
+            .encode(75.0)
+            .to_vec();
+
+        for data in [
+            encoded(image::ImageFormat::Jpeg),
+            encoded(image::ImageFormat::Png),
+            webp,
+        ] {
+            let embedded = meta.embed(data);
+            assert!(image::load_from_memory(&embedded).is_ok());
+            assert_eq!(
+                Metadata::read(&embedded, MetadataPolicy::KeepAll).xmp(),
+                Some(&xmp[..])
+            );
+            assert!(Metadata::read(&embedded, MetadataPolicy::KeepCopyright)
+                .xmp()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn webp_xmp_gets_an_extended_header() {
+        let data = ::webp::Encoder::from_rgb(&[128; 8 * 8 * 3], 8, 8)
+            .encode(75.0)
+            .to_vec();
+        let meta = Metadata {
+            xmp: Some(Bytes::from_static(b"<x:xmpmeta/>")),
+            ..Default::default()
+        };
+
+        let webp = WebP::from_bytes(Bytes::from(meta.embed(data))).unwrap();
+        let vp8x = webp.chunk_by_id(webp::CHUNK_VP8X).unwrap();
+        let header = vp8x.content().data().unwrap();
+        assert_eq!(header[0], 0x04);
+        assert_eq!(&header[4..10], &[7, 0, 0, 7, 0, 0]);
+    }
+}
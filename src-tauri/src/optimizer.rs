@@ -1,88 +1,168 @@
 use rayon::prelude::*;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
-use tauri::{Emitter, Window};
 use walkdir::WalkDir;
 
-use crate::image_ops::{generate_avif, generate_webp, process_jpg, process_png};
-use crate::tools::{get_png_tools, ToolPath};
-use crate::types::{FileStats, FinalResult, OptimizeConfig, ProgressPayload};
+use crate::assets::write_asset_manifest;
+use crate::backup::{backup_root, prune, RunBackup};
+use crate::image_ops::{
+    decode_animation, decode_source, decode_svg, generate_animated_webp, generate_avif,
+    generate_jxl, generate_raster, generate_webp, output_dimensions, process_gif, process_jpg,
+    process_png, process_svg, process_webp, render_svg, resize_to_width, variant_specs, Encoded,
+};
+use crate::manifest::ManifestStore;
+use crate::metadata::Metadata;
+use crate::reporter::{ProgressEvent, ProgressReporter};
+use crate::types::{
+    AvifSettings, ErrorKind, ErrorStage, FileError, FileErrorPayload, FileRecord, FileStats,
+    FinalResult, OptimizeConfig, ProgressPayload, SizeOutcome, VariantFormat, VariantRecord,
+};
 
 pub fn perform_optimization(
-    window: &Window,
+    reporter: &dyn ProgressReporter,
     config: OptimizeConfig,
     should_cancel: Arc<AtomicBool>,
 ) -> Result<FinalResult, String> {
     let start_time = Instant::now();
 
-    let (_tmp_dir, pq, oxi) =
-        get_png_tools().map_err(|e| format!("Failed to setup tools: {}", e))?;
-    let _ = window.emit("status_update", "Preparing files...");
+    reporter.report(ProgressEvent::Status("Preparing files...".into()));
 
-    let file_tasks = collect_file_tasks(&config)?;
+    let mut manifest = config.use_manifest.then(|| ManifestStore::new(&config));
+
+    let (mut file_tasks, up_to_date) = collect_file_tasks(&config, reporter, manifest.as_mut())?;
+    let skipped_count = up_to_date.len() as u64;
+    let found_files_count = file_tasks.len() as u64;
+
+    if config.dry_run {
+        if let Some(sample) = config.dry_run_sample {
+            file_tasks = sample_tasks(file_tasks, sample);
+        }
+    }
     let total_files_count = file_tasks.len() as u64;
+    let is_estimate = total_files_count < found_files_count;
 
-    let _ = window.emit(
-        "progress",
-        ProgressPayload {
-            total: total_files_count,
-            done: 0,
-            current_file: "Starting...".into(),
-        },
-    );
+    if skipped_count > 0 {
+        reporter.report(ProgressEvent::Status(format!(
+            "Skipping {} already optimized files",
+            skipped_count
+        )));
+    }
+
+    reporter.report(ProgressEvent::Progress(ProgressPayload {
+        total: total_files_count,
+        done: 0,
+        current_file: "Starting...".into(),
+    }));
 
     let done_counter = Arc::new(AtomicU64::new(0));
 
-    let results: Vec<FileStats> = file_tasks
+    let backup = if config.backup && config.replace && config.optimize_original && !config.dry_run {
+        Some(RunBackup::begin(&config)?)
+    } else {
+        None
+    };
+
+    let mut files: Vec<FileRecord> = file_tasks
         .par_iter()
         .map(|(src, dest)| {
-            if should_cancel.load(Ordering::Relaxed) {
-                return FileStats::default();
-            }
+            let stats = if should_cancel.load(Ordering::Relaxed) {
+                FileStats::default()
+            } else {
+                process_single_file(
+                    src,
+                    dest,
+                    &config,
+                    reporter,
+                    backup.as_ref(),
+                    &done_counter,
+                    total_files_count,
+                    &should_cancel,
+                )
+            };
+
+            let record = FileRecord {
+                source: src.to_string_lossy().to_string(),
+                destination: dest.to_string_lossy().to_string(),
+                stats,
+            };
+            reporter.report(ProgressEvent::FileFinished(record.clone()));
 
-            process_single_file(
-                src,
-                dest,
-                &config,
-                &pq,
-                &oxi,
-                window,
-                &done_counter,
-                total_files_count,
-                &should_cancel,
-            )
+            record
         })
         .collect();
 
+    // Skipped files stay in the result so the asset manifest still lists them.
+    files.extend(up_to_date.iter().map(|(src, dest)| FileRecord {
+        source: src.to_string_lossy().to_string(),
+        destination: dest.to_string_lossy().to_string(),
+        stats: existing_stats(src, dest, &config),
+    }));
+    files.sort_by(|a, b| a.source.cmp(&b.source));
+
     let is_canceled = should_cancel.load(Ordering::Relaxed);
     let duration_total_wall = start_time.elapsed().as_secs_f64();
 
     let mut total_saved = 0;
+    let mut lossless_files = 0;
+    let mut total_saved_lossless = 0;
     let mut total_original = 0;
     let mut total_optimized = 0;
     let mut total_webp_size = 0;
     let mut total_avif_size = 0;
+    let mut total_jxl_size = 0;
 
     let mut sum_cpu_opt = 0.0;
     let mut sum_cpu_webp = 0.0;
     let mut sum_cpu_avif = 0.0;
+    let mut sum_cpu_jxl = 0.0;
+
+    let mut failed_files = Vec::new();
+    let mut over_budget_files = Vec::new();
+
+    for f in &files {
+        let s = &f.stats;
+        if s.up_to_date {
+            continue;
+        }
+
+        if let Some(ref mut manifest) = manifest {
+            if s.completed && s.errors.is_empty() && !config.dry_run {
+                manifest.record(Path::new(&f.source));
+            }
+        }
 
-    for s in results {
         total_saved += s.bytes_saved;
+        if s.optimized_lossless {
+            lossless_files += 1;
+            total_saved_lossless += s.bytes_saved;
+        }
         total_original += s.original_size;
         total_optimized += s.optimized_size;
         total_webp_size += s.webp_size;
         total_avif_size += s.avif_size;
+        total_jxl_size += s.jxl_size;
 
         sum_cpu_opt += s.duration_opt;
         sum_cpu_webp += s.duration_webp;
         sum_cpu_avif += s.duration_avif;
+        sum_cpu_jxl += s.duration_jxl;
+
+        for error in &s.errors {
+            failed_files.push(FileErrorPayload {
+                path: f.source.clone(),
+                error: error.clone(),
+            });
+        }
+        if s.over_budget {
+            over_budget_files.push(f.source.clone());
+        }
     }
 
-    let total_cpu_time = sum_cpu_opt + sum_cpu_webp + sum_cpu_avif;
+    let total_cpu_time = sum_cpu_opt + sum_cpu_webp + sum_cpu_avif + sum_cpu_jxl;
     let factor = if total_cpu_time > 0.0001 {
         duration_total_wall / total_cpu_time
     } else {
@@ -91,30 +171,110 @@ pub fn perform_optimization(
 
     let processed_count = done_counter.load(Ordering::Relaxed);
 
+    if let Some(ref manifest) = manifest {
+        if !config.dry_run {
+            if let Err(e) = manifest.save() {
+                reporter.report(ProgressEvent::Status(e));
+            }
+        }
+    }
+
+    let asset_manifest = match config.asset_manifest {
+        Some(ref path) if !config.dry_run => {
+            match write_asset_manifest(&files, Path::new(path), config.picture_snippets) {
+                Ok(()) => Some(path.clone()),
+                Err(e) => {
+                    reporter.report(ProgressEvent::Status(e));
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let backup_run = match backup {
+        Some(b) if b.is_empty() => {
+            b.discard();
+            None
+        }
+        Some(b) => {
+            let id = b.id().to_string();
+            if let Ok(root) = backup_root(&config) {
+                prune(
+                    &root,
+                    &id,
+                    config.backup_max_age_days,
+                    config.backup_max_size_mb,
+                );
+            }
+            Some(id)
+        }
+        None => None,
+    };
+
+    if is_estimate {
+        let scale = found_files_count as f64 / total_files_count.max(1) as f64;
+        let project = |v: u64| (v as f64 * scale).round() as u64;
+        total_saved = project(total_saved);
+        total_saved_lossless = project(total_saved_lossless);
+        total_original = project(total_original);
+        total_optimized = project(total_optimized);
+        total_webp_size = project(total_webp_size);
+        total_avif_size = project(total_avif_size);
+        total_jxl_size = project(total_jxl_size);
+    }
+
     Ok(FinalResult {
-        total_files: total_files_count,
+        total_files: found_files_count,
         processed_files: processed_count,
+        skipped_files: skipped_count,
         is_canceled,
+        is_dry_run: config.dry_run,
+        is_estimate,
         total_size_saved: total_saved,
+        lossless_files,
+        total_size_saved_lossless: total_saved_lossless,
         duration_total: duration_total_wall,
         duration_opt: sum_cpu_opt * factor,
         duration_webp: sum_cpu_webp * factor,
         duration_avif: sum_cpu_avif * factor,
+        duration_jxl: sum_cpu_jxl * factor,
         total_size_original: total_original,
         total_size_optimized: total_optimized,
         total_size_webp: total_webp_size,
         total_size_avif: total_avif_size,
+        total_size_jxl: total_jxl_size,
+        failed_files,
+        over_budget_files,
+        files,
+        backup_run,
+        asset_manifest,
+        avif_settings: config.avif.then(|| AvifSettings::from_config(&config)),
     })
 }
 
-fn collect_file_tasks(config: &OptimizeConfig) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+fn collect_file_tasks(
+    config: &OptimizeConfig,
+    reporter: &dyn ProgressReporter,
+    mut manifest: Option<&mut ManifestStore>,
+) -> Result<(Vec<(PathBuf, PathBuf)>, Vec<(PathBuf, PathBuf)>), String> {
     let mut tasks = Vec::new();
-    let supported_exts = ["png", "jpg", "jpeg"];
+    let mut avif_files = Vec::new();
+    let supported_exts = ["png", "jpg", "jpeg", "gif", "webp", "svg"];
 
-    let is_supported = |p: &Path| {
-        p.extension()
-            .map(|ext| supported_exts.contains(&ext.to_string_lossy().to_lowercase().as_str()))
-            .unwrap_or(false)
+    // AVIF can be written but not read as a source.
+    let mut collect = |path: &Path, root_path: &Path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.contains("__optimized") {
+            return;
+        }
+        let ext = extension_of(path);
+        if ext == "avif" {
+            avif_files.push(path.to_path_buf());
+        } else if supported_exts.contains(&ext.as_str()) {
+            let dest = resolve_output_path(path, root_path, config);
+            tasks.push((path.to_path_buf(), dest, root_path.to_path_buf()));
+        }
     };
 
     for task in &config.tasks {
@@ -132,18 +292,42 @@ fn collect_file_tasks(config: &OptimizeConfig) -> Result<Vec<(PathBuf, PathBuf)>
 
         if src_path.is_dir() {
             for entry in WalkDir::new(src_path).into_iter().filter_map(|e| e.ok()) {
-                let path = entry.path();
-                if path.is_file() && is_supported(path) {
-                    let dest = resolve_output_path(path, root_path, config);
-                    tasks.push((path.to_path_buf(), dest));
+                if entry.path().is_file() {
+                    collect(entry.path(), root_path);
                 }
             }
-        } else if src_path.is_file() && is_supported(src_path) {
-            let dest = resolve_output_path(src_path, root_path, config);
-            tasks.push((src_path.to_path_buf(), dest));
+        } else if src_path.is_file() {
+            collect(src_path, root_path);
         }
     }
 
+    // The WebP/AVIF siblings and variants of an earlier run sit next to their
+    // source. Reading them back would re-encode lossy outputs on every run,
+    // and in replace mode race with the source writing the same file.
+    let bases: HashSet<PathBuf> = tasks
+        .iter()
+        .map(|(_, dest, _)| dest.with_extension(""))
+        .collect();
+    let sibling_bases: HashSet<PathBuf> = tasks
+        .iter()
+        .filter(|(src, _, _)| extension_of(src) != "webp")
+        .map(|(_, dest, _)| dest.with_extension(""))
+        .collect();
+    let is_generated = |path: &Path| {
+        let base = path.with_extension("");
+        sibling_bases.contains(&base)
+            || variant_base(&base).is_some_and(|base| bases.contains(&base))
+    };
+    tasks.retain(|(src, _, _)| extension_of(src) != "webp" || !is_generated(src));
+
+    let avif_sources = avif_files.iter().filter(|p| !is_generated(p)).count();
+    if avif_sources > 0 {
+        reporter.report(ProgressEvent::Status(format!(
+            "Skipping {} AVIF file(s): AVIF sources are not supported",
+            avif_sources
+        )));
+    }
+
     if tasks.is_empty() {
         return Err("No supported files found.".to_string());
     }
@@ -151,7 +335,52 @@ fn collect_file_tasks(config: &OptimizeConfig) -> Result<Vec<(PathBuf, PathBuf)>
     tasks.sort_by(|a, b| a.0.cmp(&b.0));
     tasks.dedup_by(|a, b| a.0 == b.0);
 
-    Ok(tasks)
+    let (tasks, skipped): (Vec<_>, Vec<_>) = match manifest {
+        Some(ref mut manifest) => tasks
+            .into_iter()
+            .partition(|(src, dest, root)| !manifest.is_up_to_date(src, dest, root)),
+        None => (tasks, Vec::new()),
+    };
+
+    let strip_root = |tasks: Vec<(PathBuf, PathBuf, PathBuf)>| -> Vec<(PathBuf, PathBuf)> {
+        tasks
+            .into_iter()
+            .map(|(src, dest, _)| (src, dest))
+            .collect()
+    };
+    Ok((strip_root(tasks), strip_root(skipped)))
+}
+
+fn extension_of(path: &Path) -> String {
+    path.extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
+
+// Strips a `-640` width or `@2x` density suffix from an extensionless path.
+fn variant_base(base: &Path) -> Option<PathBuf> {
+    let name = base.file_name()?.to_str()?;
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let stem = match name.rsplit_once('-') {
+        Some((stem, width)) if is_number(width) => stem,
+        _ => match name.strip_suffix('x')?.rsplit_once('@') {
+            Some((stem, density)) if is_number(density) => stem,
+            _ => return None,
+        },
+    };
+    Some(base.with_file_name(stem))
+}
+
+fn sample_tasks(tasks: Vec<(PathBuf, PathBuf)>, sample: usize) -> Vec<(PathBuf, PathBuf)> {
+    let len = tasks.len();
+    if sample == 0 || sample >= len {
+        return tasks;
+    }
+
+    (0..sample)
+        .map(|k| tasks[k * len / sample].clone())
+        .collect()
 }
 
 fn resolve_output_path(src: &Path, root_source: &Path, config: &OptimizeConfig) -> PathBuf {
@@ -175,72 +404,291 @@ fn resolve_output_path(src: &Path, root_source: &Path, config: &OptimizeConfig)
     }
 }
 
+fn resolve_variant_path(dest: &Path, suffix: &str) -> PathBuf {
+    let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = dest.extension().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!("{}{}.{}", stem, suffix, ext))
+}
+
 #[allow(clippy::too_many_arguments)]
 fn process_single_file(
     src: &Path,
     dest: &Path,
     config: &OptimizeConfig,
-    pq: &ToolPath,
-    oxi: &ToolPath,
-    window: &Window,
+    reporter: &dyn ProgressReporter,
+    backup: Option<&RunBackup>,
     done_counter: &Arc<AtomicU64>,
     total_files: u64,
     should_cancel: &Arc<AtomicBool>,
 ) -> FileStats {
     let t_start = Instant::now();
-    let _ = window.emit(
-        "file_start",
-        src.file_name().unwrap_or_default().to_string_lossy(),
-    );
+    reporter.report(ProgressEvent::FileStarted(
+        src.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    ));
 
     if should_cancel.load(Ordering::Relaxed) {
         return FileStats::default();
     }
 
-    if src != dest {
+    if src != dest && !config.dry_run {
         if let Some(parent) = dest.parent() {
             let _ = fs::create_dir_all(parent);
         }
 
         if config.optimize_original {
-            if fs::copy(src, dest).is_err() {
-                return FileStats::default();
+            if let Err(e) = fs::copy(src, dest) {
+                let mut errors = Vec::new();
+                let error = FileError::new(ErrorStage::Prepare, ErrorKind::Io, &e);
+                record_error(reporter, src, &mut errors, error);
+                return FileStats {
+                    optimized_outcome: SizeOutcome::Failed,
+                    errors,
+                    ..Default::default()
+                };
             }
         }
+    } else if let Some(backup) = backup {
+        if let Err(e) = backup.store(src) {
+            let mut errors = Vec::new();
+            let error = FileError::new(ErrorStage::Prepare, ErrorKind::Io, &e);
+            record_error(reporter, src, &mut errors, error);
+            return FileStats {
+                optimized_outcome: SizeOutcome::Failed,
+                errors,
+                ..Default::default()
+            };
+        }
+    }
+
+    if is_svg(src) {
+        return process_svg_file(
+            src,
+            dest,
+            config,
+            reporter,
+            done_counter,
+            total_files,
+            should_cancel,
+            t_start,
+        );
     }
 
+    let mut errors = Vec::new();
+
     let original_size = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
     let mut webp_size = 0;
     let mut avif_size = 0;
+    let mut jxl_size = 0;
     let mut duration_webp = 0.0;
     let mut duration_avif = 0.0;
+    let mut duration_jxl = 0.0;
+    let mut webp_outcome = SizeOutcome::Skipped;
+    let mut avif_outcome = SizeOutcome::Skipped;
+    let mut jxl_outcome = SizeOutcome::Skipped;
+    let mut jxl_lossless = false;
+    let mut webp_target = None;
+    let mut avif_target = None;
+    let mut webp_dimensions = None;
+    let mut avif_dimensions = None;
+    let mut over_budget = false;
+    let mut variants = Vec::new();
 
-    if config.webp || config.avif {
-        if let Ok(img) = image::open(src) {
-            if config.webp && !should_cancel.load(Ordering::Relaxed) {
-                let t = Instant::now();
-                webp_size = generate_webp(&img, dest, 75.0);
-                duration_webp = t.elapsed().as_secs_f64();
+    let (img, meta) = match decode_source(src, config) {
+        Ok((img, meta)) => (Some(img), meta),
+        Err(e) => {
+            record_error(reporter, src, &mut errors, e);
+            (None, Metadata::default())
+        }
+    };
+    let (width, height) = img.as_ref().map_or((0, 0), |i| (i.width(), i.height()));
+
+    let source_ext = src
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    // Animations skip the still-image encoders, which would keep only the first frame.
+    let may_animate = match source_ext.as_str() {
+        "gif" => config.webp || config.avif,
+        "webp" => config.optimize_original || config.avif,
+        _ => false,
+    };
+    let animation = if may_animate && img.is_some() {
+        match decode_animation(src, config) {
+            Ok(animation) => animation,
+            Err(e) => {
+                record_error(reporter, src, &mut errors, e);
+                None
             }
+        }
+    } else {
+        None
+    };
+
+    if let Some(ref img) = img {
+        // A WebP source is its own next-gen output; it is re-encoded as the
+        // original below rather than written twice.
+        if config.webp && source_ext != "webp" && !should_cancel.load(Ordering::Relaxed) {
+            let t = Instant::now();
+            let result = match animation {
+                Some(ref animation) => {
+                    generate_animated_webp(animation, dest, original_size, config)
+                }
+                None => generate_webp(img, &meta, dest, original_size, config),
+            };
+            match result {
+                Ok(res) => {
+                    (webp_size, webp_outcome, webp_target) = (res.size, res.outcome, res.target);
+                    webp_dimensions = res.dimensions;
+                    over_budget |= res.over_budget;
+                }
+                Err(e) => {
+                    webp_outcome = SizeOutcome::Failed;
+                    record_error(reporter, src, &mut errors, e);
+                }
+            }
+            duration_webp = t.elapsed().as_secs_f64();
+        }
+
+        if config.avif && animation.is_none() && !should_cancel.load(Ordering::Relaxed) {
+            let t = Instant::now();
+            match generate_avif(img, &meta, dest, original_size, config) {
+                Ok(res) => {
+                    (avif_size, avif_outcome, avif_target) = (res.size, res.outcome, res.target);
+                    avif_dimensions = res.dimensions;
+                    over_budget |= res.over_budget;
+                }
+                Err(e) => {
+                    avif_outcome = SizeOutcome::Failed;
+                    record_error(reporter, src, &mut errors, e);
+                }
+            }
+            duration_avif = t.elapsed().as_secs_f64();
+        }
+
+        if config.jxl && animation.is_none() && !should_cancel.load(Ordering::Relaxed) {
+            let t = Instant::now();
+            match generate_jxl(img, &meta, src, dest, original_size, config) {
+                Ok(res) => {
+                    (jxl_size, jxl_outcome, jxl_lossless) = (res.size, res.outcome, res.lossless);
+                }
+                Err(e) => {
+                    jxl_outcome = SizeOutcome::Failed;
+                    record_error(reporter, src, &mut errors, e);
+                }
+            }
+            duration_jxl = t.elapsed().as_secs_f64();
+        }
+
+        let specs = match animation {
+            Some(_) => Vec::new(),
+            None => variant_specs(img.width(), config),
+        };
+        for spec in specs {
+            if should_cancel.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let scaled;
+            let variant_img = if spec.width == img.width() {
+                img
+            } else {
+                scaled = resize_to_width(img, spec.width, config);
+                &scaled
+            };
+            let base = resolve_variant_path(dest, &spec.suffix);
+
+            for (format, enabled) in [
+                (VariantFormat::Webp, config.webp),
+                (VariantFormat::Avif, config.avif),
+            ] {
+                if !enabled {
+                    continue;
+                }
 
-            if config.avif && !should_cancel.load(Ordering::Relaxed) {
                 let t = Instant::now();
-                avif_size = generate_avif(&img, dest);
-                duration_avif = t.elapsed().as_secs_f64();
+                let (result, duration) = match format {
+                    VariantFormat::Webp => (
+                        generate_webp(variant_img, &meta, &base, original_size, config),
+                        &mut duration_webp,
+                    ),
+                    VariantFormat::Avif => (
+                        generate_avif(variant_img, &meta, &base, original_size, config),
+                        &mut duration_avif,
+                    ),
+                    // Only SVG sources get PNG variants.
+                    VariantFormat::Png => continue,
+                };
+                *duration += t.elapsed().as_secs_f64();
+
+                let (outcome, size, dimensions) = match result {
+                    Ok(res) => {
+                        over_budget |= res.over_budget;
+                        (res.outcome, res.size, res.dimensions)
+                    }
+                    Err(e) => {
+                        record_error(reporter, src, &mut errors, e);
+                        (SizeOutcome::Failed, 0, None)
+                    }
+                };
+                let (width, height) =
+                    dimensions.unwrap_or((variant_img.width(), variant_img.height()));
+                variants.push(VariantRecord {
+                    format,
+                    descriptor: spec.descriptor.clone(),
+                    path: base
+                        .with_extension(format.extension())
+                        .to_string_lossy()
+                        .to_string(),
+                    width,
+                    height,
+                    size,
+                    outcome,
+                });
             }
         }
+    } else {
+        if config.webp {
+            webp_outcome = SizeOutcome::Failed;
+        }
+        if config.avif {
+            avif_outcome = SizeOutcome::Failed;
+        }
+        if config.jxl {
+            jxl_outcome = SizeOutcome::Failed;
+        }
     }
 
     if should_cancel.load(Ordering::Relaxed) {
         return FileStats {
             bytes_saved: 0,
+            width,
+            height,
             original_size,
             optimized_size: original_size,
             webp_size,
             avif_size,
+            jxl_size,
             duration_opt: 0.0,
             duration_webp,
             duration_avif,
+            duration_jxl,
+            webp_outcome,
+            avif_outcome,
+            jxl_outcome,
+            webp_target,
+            avif_target,
+            jxl_lossless,
+            over_budget,
+            webp_dimensions,
+            avif_dimensions,
+            variants,
+            errors,
+            ..Default::default()
         };
     }
 
@@ -252,16 +700,36 @@ fn process_single_file(
 
     let t_opt_start = Instant::now();
 
-    let (new_size, bytes_saved) = if config.optimize_original {
-        if src != dest && !dest.exists() {
-             (0, 0)
-        } else {
-            let size = if ext == "png" {
-                process_png(dest, pq, oxi, config.png_min, config.png_max)
+    let mut optimized_lossless = false;
+    let mut optimized_dimensions = None;
+    let (new_size, bytes_saved, optimized_outcome, optimized_target) = if config.optimize_original {
+        if src != dest && !config.dry_run && !dest.exists() {
+            (0, 0, SizeOutcome::Skipped, None)
+        } else if let Some(ref img) = img {
+            let target = if config.dry_run { src } else { dest };
+            let result = if ext == "png" {
+                process_png(target, img, &meta, config)
             } else if ["jpg", "jpeg"].contains(&ext.as_str()) {
-                process_jpg(dest, config.jpg_q)
+                process_jpg(target, img, &meta, config)
+            } else if ext == "gif" {
+                process_gif(target, config)
+            } else if ext == "webp" {
+                process_webp(target, img, animation.as_ref(), &meta, config)
             } else {
-                original_size
+                Ok(Encoded::new(original_size, SizeOutcome::KeptOriginal))
+            };
+
+            let (size, outcome, quality) = match result {
+                Ok(res) => {
+                    over_budget |= res.over_budget;
+                    optimized_lossless = res.lossless;
+                    optimized_dimensions = res.dimensions;
+                    (res.size, res.outcome, res.target)
+                }
+                Err(e) => {
+                    record_error(reporter, src, &mut errors, e);
+                    (original_size, SizeOutcome::Failed, None)
+                }
             };
 
             let saved = if original_size > size {
@@ -269,37 +737,32 @@ fn process_single_file(
             } else {
                 0
             };
-            (size, saved)
+            (size, saved, outcome, quality)
+        } else {
+            (original_size, 0, SizeOutcome::Failed, None)
         }
     } else {
-        (0, 0)
+        (0, 0, SizeOutcome::Skipped, None)
     };
 
     let duration_opt_pure = t_opt_start.elapsed().as_secs_f64();
 
-    let done = done_counter.fetch_add(1, Ordering::Relaxed) + 1;
-    let _ = window.emit(
-        "progress",
-        ProgressPayload {
-            total: total_files,
-            done,
-            current_file: src
-                .file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string(),
-        },
-    );
+    report_done(reporter, src, done_counter, total_files);
 
     let total_file_time = t_start.elapsed().as_secs_f64();
-    let overhead = (total_file_time - duration_opt_pure - duration_webp - duration_avif).max(0.0);
+    let overhead =
+        (total_file_time - duration_opt_pure - duration_webp - duration_avif - duration_jxl)
+            .max(0.0);
 
     FileStats {
         bytes_saved,
+        width,
+        height,
         original_size,
         optimized_size: new_size,
         webp_size,
         avif_size,
+        jxl_size,
         duration_opt: if config.optimize_original {
             duration_opt_pure + overhead
         } else {
@@ -307,5 +770,266 @@ fn process_single_file(
         },
         duration_webp,
         duration_avif,
+        duration_jxl,
+        optimized_outcome,
+        webp_outcome,
+        avif_outcome,
+        jxl_outcome,
+        optimized_target,
+        webp_target,
+        avif_target,
+        optimized_lossless,
+        jxl_lossless,
+        over_budget,
+        optimized_dimensions,
+        webp_dimensions,
+        avif_dimensions,
+        variants,
+        errors,
+        completed: true,
+        up_to_date: false,
     }
 }
+
+// What an earlier run left for a file the manifest skipped. Sizes and
+// dimensions are read from disk; a variant that can't be read is assumed to
+// follow the source's aspect ratio.
+fn existing_stats(src: &Path, dest: &Path, config: &OptimizeConfig) -> FileStats {
+    let size_of = |path: &Path| fs::metadata(path).map(|m| m.len()).ok();
+    let original = if dest.is_file() { dest } else { src };
+
+    let (width, height) = if is_svg(src) {
+        decode_svg(src)
+            .map(|svg| svg.dimensions())
+            .unwrap_or_default()
+    } else {
+        image::image_dimensions(original).unwrap_or_default()
+    };
+
+    // A WebP source is its own output, not a next-gen copy.
+    let output = |enabled: bool, ext: &str| {
+        let path = dest.with_extension(ext);
+        match size_of(&path) {
+            Some(size) if enabled && path != dest => {
+                let dimensions = output_dimensions(&path).filter(|&dims| dims != (width, height));
+                (size, SizeOutcome::Optimized, dimensions)
+            }
+            _ => (0, SizeOutcome::Skipped, None),
+        }
+    };
+    let (webp_size, webp_outcome, webp_dimensions) = output(config.webp, "webp");
+    let (avif_size, avif_outcome, avif_dimensions) = output(config.avif, "avif");
+    let (jxl_size, jxl_outcome, _) = output(config.jxl, "jxl");
+
+    let (specs, formats): (Vec<(String, String, u32)>, &[VariantFormat]) = if is_svg(src) {
+        let specs = config
+            .svg_raster_widths
+            .iter()
+            .map(|&w| (format!("{}w", w), format!("-{}", w), w))
+            .collect();
+        (specs, config.svg_raster_format.formats())
+    } else {
+        let specs = variant_specs(width, config)
+            .into_iter()
+            .map(|spec| (spec.descriptor, spec.suffix, spec.width))
+            .collect();
+        (specs, &[VariantFormat::Webp, VariantFormat::Avif])
+    };
+
+    let mut variants = Vec::new();
+    for (descriptor, suffix, variant_width) in specs {
+        let base = resolve_variant_path(dest, &suffix);
+        for &format in formats {
+            let path = base.with_extension(format.extension());
+            let Some(size) = size_of(&path) else {
+                continue;
+            };
+            let (variant_width, variant_height) = output_dimensions(&path).unwrap_or((
+                variant_width,
+                (u64::from(height) * u64::from(variant_width) / u64::from(width.max(1))).max(1)
+                    as u32,
+            ));
+            variants.push(VariantRecord {
+                format,
+                descriptor: descriptor.clone(),
+                path: path.to_string_lossy().to_string(),
+                width: variant_width,
+                height: variant_height,
+                size,
+                outcome: SizeOutcome::Optimized,
+            });
+        }
+    }
+
+    FileStats {
+        width,
+        height,
+        original_size: size_of(src).unwrap_or(0),
+        optimized_size: size_of(original).unwrap_or(0),
+        webp_size,
+        avif_size,
+        jxl_size,
+        webp_outcome,
+        avif_outcome,
+        jxl_outcome,
+        webp_dimensions,
+        avif_dimensions,
+        variants,
+        completed: true,
+        up_to_date: true,
+        ..Default::default()
+    }
+}
+
+fn is_svg(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+}
+
+// SVGs skip the raster pipeline: the original is minified in place, and
+// `svg_raster_widths` renders PNG/WebP copies recorded as variants.
+#[allow(clippy::too_many_arguments)]
+fn process_svg_file(
+    src: &Path,
+    dest: &Path,
+    config: &OptimizeConfig,
+    reporter: &dyn ProgressReporter,
+    done_counter: &Arc<AtomicU64>,
+    total_files: u64,
+    should_cancel: &Arc<AtomicBool>,
+    t_start: Instant,
+) -> FileStats {
+    let mut errors = Vec::new();
+    let original_size = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
+
+    let svg = match decode_svg(src) {
+        Ok(svg) => Some(svg),
+        Err(e) => {
+            record_error(reporter, src, &mut errors, e);
+            None
+        }
+    };
+    let (width, height) = svg.as_ref().map_or((0, 0), |s| s.dimensions());
+
+    let mut variants = Vec::new();
+    if let Some(ref svg) = svg {
+        for &raster_width in &config.svg_raster_widths {
+            if should_cancel.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let img = match render_svg(svg, raster_width) {
+                Ok(img) => img,
+                Err(e) => {
+                    record_error(reporter, src, &mut errors, e);
+                    continue;
+                }
+            };
+            let base = resolve_variant_path(dest, &format!("-{}", raster_width));
+
+            for &format in config.svg_raster_format.formats() {
+                let (outcome, size) = match generate_raster(&img, format, &base, config) {
+                    Ok(res) => (res.outcome, res.size),
+                    Err(e) => {
+                        record_error(reporter, src, &mut errors, e);
+                        (SizeOutcome::Failed, 0)
+                    }
+                };
+                variants.push(VariantRecord {
+                    format,
+                    descriptor: format!("{}w", raster_width),
+                    path: base
+                        .with_extension(format.extension())
+                        .to_string_lossy()
+                        .to_string(),
+                    width: img.width(),
+                    height: img.height(),
+                    size,
+                    outcome,
+                });
+            }
+        }
+    }
+
+    if should_cancel.load(Ordering::Relaxed) {
+        return FileStats {
+            width,
+            height,
+            original_size,
+            optimized_size: original_size,
+            variants,
+            errors,
+            ..Default::default()
+        };
+    }
+
+    let (optimized_size, bytes_saved, optimized_outcome) = if config.optimize_original {
+        if src != dest && !config.dry_run && !dest.exists() {
+            (0, 0, SizeOutcome::Skipped)
+        } else if let Some(ref svg) = svg {
+            let target = if config.dry_run { src } else { dest };
+            match process_svg(target, svg, config) {
+                Ok(res) => (
+                    res.size,
+                    original_size.saturating_sub(res.size),
+                    res.outcome,
+                ),
+                Err(e) => {
+                    record_error(reporter, src, &mut errors, e);
+                    (original_size, 0, SizeOutcome::Failed)
+                }
+            }
+        } else {
+            (original_size, 0, SizeOutcome::Failed)
+        }
+    } else {
+        (0, 0, SizeOutcome::Skipped)
+    };
+
+    report_done(reporter, src, done_counter, total_files);
+
+    FileStats {
+        bytes_saved,
+        width,
+        height,
+        original_size,
+        optimized_size,
+        duration_opt: t_start.elapsed().as_secs_f64(),
+        optimized_outcome,
+        variants,
+        errors,
+        completed: true,
+        ..Default::default()
+    }
+}
+
+fn report_done(
+    reporter: &dyn ProgressReporter,
+    src: &Path,
+    done_counter: &Arc<AtomicU64>,
+    total_files: u64,
+) {
+    let done = done_counter.fetch_add(1, Ordering::Relaxed) + 1;
+    reporter.report(ProgressEvent::Progress(ProgressPayload {
+        total: total_files,
+        done,
+        current_file: src
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    }));
+}
+
+fn record_error(
+    reporter: &dyn ProgressReporter,
+    src: &Path,
+    errors: &mut Vec<FileError>,
+    error: FileError,
+) {
+    reporter.report(ProgressEvent::Error(FileErrorPayload {
+        path: src.to_string_lossy().to_string(),
+        error: error.clone(),
+    }));
+    errors.push(error);
+}
@@ -0,0 +1,94 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::types::{FileRecord, FinalResult, SizeOutcome, TargetQuality};
+
+const CSV_HEADER: &str = "source,destination,original_size,optimized_size,webp_size,avif_size,jxl_size,\
+duration_opt,duration_webp,duration_avif,duration_jxl,optimized_outcome,webp_outcome,avif_outcome,jxl_outcome,\
+optimized_quality,optimized_dssim,webp_quality,webp_dssim,avif_quality,avif_dssim,optimized_lossless,jxl_lossless,over_budget,up_to_date,variants,errors";
+
+pub fn write_report(result: &FinalResult, path: &Path) -> Result<(), String> {
+    let ext = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    let content = match ext.as_str() {
+        "json" => serde_json::to_string_pretty(result).map_err(|e| e.to_string())?,
+        "csv" => to_csv(&result.files),
+        _ => return Err(format!("Unsupported report format: '{}'", ext)),
+    };
+
+    fs::write(path, content).map_err(|e| format!("Failed to write report: {}", e))
+}
+
+fn to_csv(files: &[FileRecord]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+
+    for f in files {
+        let s = &f.stats;
+        let errors = s
+            .errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let variants = s
+            .variants
+            .iter()
+            .map(|v| match v.outcome {
+                SizeOutcome::Optimized => v.path.clone(),
+                outcome => format!("{} ({})", v.path, outcome.as_str()),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{:.3},{:.3},{:.3},{:.3},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&f.source),
+            csv_field(&f.destination),
+            s.original_size,
+            s.optimized_size,
+            s.webp_size,
+            s.avif_size,
+            s.jxl_size,
+            s.duration_opt,
+            s.duration_webp,
+            s.duration_avif,
+            s.duration_jxl,
+            s.optimized_outcome.as_str(),
+            s.webp_outcome.as_str(),
+            s.avif_outcome.as_str(),
+            s.jxl_outcome.as_str(),
+            target_fields(s.optimized_target),
+            target_fields(s.webp_target),
+            target_fields(s.avif_target),
+            s.optimized_lossless,
+            s.jxl_lossless,
+            s.over_budget,
+            s.up_to_date,
+            csv_field(&variants),
+            csv_field(&errors),
+        );
+    }
+
+    out
+}
+
+fn target_fields(target: Option<TargetQuality>) -> String {
+    target
+        .map(|t| format!("{},{:.6}", t.quality, t.dssim))
+        .unwrap_or_else(|| ",".to_string())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+#[cfg(feature = "gui")]
+use tauri::{Emitter, Window};
+
+use crate::types::{FileErrorPayload, FileRecord, ProgressPayload};
+
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    Status(String),
+    FileStarted(String),
+    FileFinished(FileRecord),
+    Progress(ProgressPayload),
+    Error(FileErrorPayload),
+}
+
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
+
+#[cfg(feature = "gui")]
+impl ProgressReporter for Window {
+    fn report(&self, event: ProgressEvent) {
+        let _ = match event {
+            ProgressEvent::Status(message) => self.emit("status_update", message),
+            ProgressEvent::FileStarted(name) => self.emit("file_start", name),
+            ProgressEvent::FileFinished(payload) => self.emit("file_done", payload),
+            ProgressEvent::Progress(payload) => self.emit("progress", payload),
+            ProgressEvent::Error(payload) => self.emit("file_error", payload),
+        };
+    }
+}
+
+pub struct ChannelReporter {
+    tx: Sender<ProgressEvent>,
+}
+
+impl ChannelReporter {
+    pub fn new() -> (Self, Receiver<ProgressEvent>) {
+        let (tx, rx) = channel();
+        (Self { tx }, rx)
+    }
+}
+
+impl ProgressReporter for ChannelReporter {
+    fn report(&self, event: ProgressEvent) {
+        let _ = self.tx.send(event);
+    }
+}
+
+pub struct NoopReporter;
+
+impl ProgressReporter for NoopReporter {
+    fn report(&self, _event: ProgressEvent) {}
+}
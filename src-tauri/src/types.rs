@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 
@@ -18,61 +19,561 @@ pub struct AppState {
     pub last_result: Mutex<Option<FinalResult>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileTask {
     pub path: String,
     pub root: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OptimizeConfig {
     pub tasks: Vec<FileTask>,
     pub jpg_q: u8,
     pub png_min: u8,
     pub png_max: u8,
+    #[serde(default)]
+    pub jpg_lossless: bool,
+    #[serde(default)]
+    pub jpg_subsampling: ChromaSubsampling,
+    #[serde(default = "default_true")]
+    pub jpg_progressive: bool,
+    #[serde(default = "default_true")]
+    pub jpg_trellis: bool,
+    #[serde(default)]
+    pub jpg_smoothing: u8,
     pub webp: bool,
     pub avif: bool,
+    #[serde(default = "default_webp_quality")]
+    pub webp_quality: f32,
+    #[serde(default)]
+    pub webp_lossless: WebpLossless,
+    #[serde(default = "default_webp_near_lossless")]
+    pub webp_near_lossless: u8,
+    #[serde(default = "default_webp_alpha_quality")]
+    pub webp_alpha_quality: u8,
+    #[serde(default = "default_webp_method")]
+    pub webp_method: u8,
+    #[serde(default = "default_avif_quality")]
+    pub avif_quality: f32,
+    #[serde(default = "default_avif_alpha_quality")]
+    pub avif_alpha_quality: f32,
+    #[serde(default = "default_avif_speed")]
+    pub avif_speed: u8,
+    #[serde(default = "default_avif_bit_depth")]
+    pub avif_bit_depth: u8,
+    #[serde(default)]
+    pub avif_color_model: AvifColorModel,
+    #[serde(default)]
+    pub jxl: bool,
+    #[serde(default = "default_jxl_quality")]
+    pub jxl_quality: f32,
+    #[serde(default = "default_jxl_effort")]
+    pub jxl_effort: u8,
+    #[serde(default = "default_true")]
+    pub jxl_transcode_jpeg: bool,
+    // Applies the metadata policy to transcoded JPEGs too, at the cost of
+    // bit-exact reconstruction of the original.
+    #[serde(default)]
+    pub jxl_strip_metadata: bool,
+    #[serde(default)]
+    pub target_dssim: Option<f64>,
+    #[serde(default)]
+    pub jpg_max_bytes: Option<u64>,
+    #[serde(default)]
+    pub webp_max_bytes: Option<u64>,
+    #[serde(default)]
+    pub avif_max_bytes: Option<u64>,
+    #[serde(default = "default_budget_min_quality")]
+    pub budget_min_quality: u8,
+    #[serde(default)]
+    pub budget_downscale: bool,
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub resize_fit: ResizeFit,
+    #[serde(default)]
+    pub resize_filter: ResizeFilter,
+    #[serde(default = "default_true")]
+    pub no_upscale: bool,
+    #[serde(default)]
+    pub variant_widths: Vec<u32>,
+    #[serde(default)]
+    pub variant_densities: Vec<u32>,
+    #[serde(default)]
+    pub variant_base_width: Option<u32>,
+    #[serde(default)]
+    pub asset_manifest: Option<String>,
+    #[serde(default)]
+    pub picture_snippets: bool,
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    // `None` follows the metadata policy.
+    #[serde(default)]
+    pub icc: Option<IccPolicy>,
+    #[serde(default = "default_svg_precision")]
+    pub svg_precision: u8,
+    #[serde(default)]
+    pub svg_raster_widths: Vec<u32>,
+    #[serde(default)]
+    pub svg_raster_format: SvgRasterFormat,
     #[serde(default = "default_true")]
     pub optimize_original: bool,
     pub replace: bool,
     pub output_dir: Option<String>,
+    #[serde(default)]
+    pub min_saving_percent: f32,
+    #[serde(default)]
+    pub use_manifest: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub dry_run_sample: Option<usize>,
+    #[serde(default)]
+    pub backup: bool,
+    #[serde(default)]
+    pub backup_dir: Option<String>,
+    #[serde(default = "default_backup_max_age_days")]
+    pub backup_max_age_days: u64,
+    #[serde(default = "default_backup_max_size_mb")]
+    pub backup_max_size_mb: u64,
+}
+
+impl Default for OptimizeConfig {
+    fn default() -> Self {
+        Self {
+            tasks: Vec::new(),
+            jpg_q: 80,
+            png_min: 65,
+            png_max: 80,
+            jpg_lossless: false,
+            jpg_subsampling: ChromaSubsampling::default(),
+            jpg_progressive: true,
+            jpg_trellis: true,
+            jpg_smoothing: 0,
+            webp: false,
+            avif: false,
+            webp_quality: default_webp_quality(),
+            webp_lossless: WebpLossless::default(),
+            webp_near_lossless: default_webp_near_lossless(),
+            webp_alpha_quality: default_webp_alpha_quality(),
+            webp_method: default_webp_method(),
+            avif_quality: default_avif_quality(),
+            avif_alpha_quality: default_avif_alpha_quality(),
+            avif_speed: default_avif_speed(),
+            avif_bit_depth: default_avif_bit_depth(),
+            avif_color_model: AvifColorModel::default(),
+            jxl: false,
+            jxl_quality: default_jxl_quality(),
+            jxl_effort: default_jxl_effort(),
+            jxl_transcode_jpeg: true,
+            jxl_strip_metadata: false,
+            target_dssim: None,
+            jpg_max_bytes: None,
+            webp_max_bytes: None,
+            avif_max_bytes: None,
+            budget_min_quality: default_budget_min_quality(),
+            budget_downscale: false,
+            max_width: None,
+            max_height: None,
+            resize_fit: ResizeFit::default(),
+            resize_filter: ResizeFilter::default(),
+            no_upscale: true,
+            variant_widths: Vec::new(),
+            variant_densities: Vec::new(),
+            variant_base_width: None,
+            asset_manifest: None,
+            picture_snippets: false,
+            metadata: MetadataPolicy::default(),
+            icc: None,
+            svg_precision: default_svg_precision(),
+            svg_raster_widths: Vec::new(),
+            svg_raster_format: SvgRasterFormat::default(),
+            optimize_original: true,
+            replace: false,
+            output_dir: None,
+            min_saving_percent: 0.0,
+            use_manifest: false,
+            dry_run: false,
+            dry_run_sample: None,
+            backup: false,
+            backup_dir: None,
+            backup_max_age_days: default_backup_max_age_days(),
+            backup_max_size_mb: default_backup_max_size_mb(),
+        }
+    }
 }
 
 fn default_true() -> bool {
     true
 }
 
-#[derive(Clone, Serialize)]
+fn default_webp_quality() -> f32 {
+    75.0
+}
+
+fn default_webp_near_lossless() -> u8 {
+    100
+}
+
+fn default_webp_alpha_quality() -> u8 {
+    100
+}
+
+fn default_webp_method() -> u8 {
+    4
+}
+
+fn default_avif_quality() -> f32 {
+    65.0
+}
+
+fn default_avif_alpha_quality() -> f32 {
+    70.0
+}
+
+fn default_avif_speed() -> u8 {
+    4
+}
+
+fn default_avif_bit_depth() -> u8 {
+    10
+}
+
+fn default_jxl_quality() -> f32 {
+    90.0
+}
+
+fn default_jxl_effort() -> u8 {
+    7
+}
+
+fn default_svg_precision() -> u8 {
+    3
+}
+
+fn default_budget_min_quality() -> u8 {
+    30
+}
+
+fn default_backup_max_age_days() -> u64 {
+    30
+}
+
+fn default_backup_max_size_mb() -> u64 {
+    2048
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "444")]
+    Yuv444,
+    #[serde(rename = "422")]
+    Yuv422,
+    #[default]
+    #[serde(rename = "420")]
+    Yuv420,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebpLossless {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AvifColorModel {
+    #[default]
+    YCbCr,
+    Rgb,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AvifSettings {
+    pub quality: f32,
+    pub alpha_quality: f32,
+    pub speed: u8,
+    pub bit_depth: u8,
+    pub color_model: AvifColorModel,
+}
+
+impl AvifSettings {
+    pub fn from_config(config: &OptimizeConfig) -> Self {
+        Self {
+            quality: config.avif_quality.clamp(1.0, 100.0),
+            alpha_quality: config.avif_alpha_quality.clamp(1.0, 100.0),
+            speed: config.avif_speed.clamp(1, 10),
+            bit_depth: if config.avif_bit_depth >= 10 { 10 } else { 8 },
+            color_model: config.avif_color_model,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFit {
+    #[default]
+    Contain,
+    Cover,
+    Exact,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    #[default]
+    StripAll,
+    // EXIF Copyright and Artist plus the ICC profile.
+    KeepCopyright,
+    // EXIF, ICC and XMP.
+    KeepAll,
+}
+
+impl MetadataPolicy {
+    // The ICC handling used when none is set explicitly: stripping drops the
+    // profile too, after converting the pixels so the colours don't shift.
+    pub fn default_icc(self) -> IccPolicy {
+        match self {
+            MetadataPolicy::StripAll => IccPolicy::Srgb,
+            MetadataPolicy::KeepCopyright | MetadataPolicy::KeepAll => IccPolicy::Preserve,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IccPolicy {
+    #[default]
+    Preserve,
+    Srgb,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SvgRasterFormat {
+    #[default]
+    Png,
+    Webp,
+    Both,
+}
+
+impl SvgRasterFormat {
+    pub fn formats(&self) -> &'static [VariantFormat] {
+        match self {
+            SvgRasterFormat::Png => &[VariantFormat::Png],
+            SvgRasterFormat::Webp => &[VariantFormat::Webp],
+            SvgRasterFormat::Both => &[VariantFormat::Png, VariantFormat::Webp],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VariantFormat {
+    Webp,
+    Avif,
+    Png,
+}
+
+impl VariantFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VariantFormat::Webp => "webp",
+            VariantFormat::Avif => "avif",
+            VariantFormat::Png => "png",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VariantRecord {
+    pub format: VariantFormat,
+    pub descriptor: String,
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    // Zero unless the variant was written.
+    pub size: u64,
+    pub outcome: SizeOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TargetQuality {
+    pub quality: f32,
+    pub dssim: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ProgressPayload {
     pub total: u64,
     pub done: u64,
     pub current_file: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRecord {
+    pub source: String,
+    pub destination: String,
+    #[serde(flatten)]
+    pub stats: FileStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileErrorPayload {
+    pub path: String,
+    pub error: FileError,
+}
+
 #[derive(Clone, Serialize)]
 pub struct FinalResult {
     pub total_files: u64,
     pub processed_files: u64,
+    pub skipped_files: u64,
     pub is_canceled: bool,
+    pub is_dry_run: bool,
+    pub is_estimate: bool,
     pub total_size_saved: u64,
+    pub lossless_files: u64,
+    pub total_size_saved_lossless: u64,
     pub duration_total: f64,
     pub duration_opt: f64,
     pub duration_webp: f64,
     pub duration_avif: f64,
+    pub duration_jxl: f64,
     pub total_size_original: u64,
     pub total_size_optimized: u64,
     pub total_size_webp: u64,
     pub total_size_avif: u64,
+    pub total_size_jxl: u64,
+    pub failed_files: Vec<FileErrorPayload>,
+    pub over_budget_files: Vec<String>,
+    pub files: Vec<FileRecord>,
+    pub backup_run: Option<String>,
+    pub asset_manifest: Option<String>,
+    pub avif_settings: Option<AvifSettings>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UndoResult {
+    pub run_id: String,
+    pub restored: usize,
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeOutcome {
+    #[default]
+    Skipped,
+    Optimized,
+    KeptOriginal,
+    Failed,
+}
+
+impl SizeOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SizeOutcome::Skipped => "skipped",
+            SizeOutcome::Optimized => "optimized",
+            SizeOutcome::KeptOriginal => "kept_original",
+            SizeOutcome::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorStage {
+    Prepare,
+    Original,
+    Webp,
+    Avif,
+    Jxl,
+    Raster,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Io,
+    Decode,
+    Encode,
+    Verify,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileError {
+    pub stage: ErrorStage,
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(stage: ErrorStage, kind: ErrorKind, err: &dyn Display) -> Self {
+        Self {
+            stage,
+            kind,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} error: {}",
+            self.stage, self.kind, self.message
+        )
+    }
 }
 
-#[derive(Default)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct FileStats {
     pub bytes_saved: u64,
+    pub width: u32,
+    pub height: u32,
     pub original_size: u64,
     pub optimized_size: u64,
     pub webp_size: u64,
     pub avif_size: u64,
+    pub jxl_size: u64,
     pub duration_opt: f64,
     pub duration_webp: f64,
     pub duration_avif: f64,
+    pub duration_jxl: f64,
+    pub optimized_outcome: SizeOutcome,
+    pub webp_outcome: SizeOutcome,
+    pub avif_outcome: SizeOutcome,
+    pub jxl_outcome: SizeOutcome,
+    pub optimized_target: Option<TargetQuality>,
+    pub webp_target: Option<TargetQuality>,
+    pub avif_target: Option<TargetQuality>,
+    pub optimized_lossless: bool,
+    pub jxl_lossless: bool,
+    pub over_budget: bool,
+    // Set for an output a byte budget downscaled below `width` x `height`.
+    pub optimized_dimensions: Option<(u32, u32)>,
+    pub webp_dimensions: Option<(u32, u32)>,
+    pub avif_dimensions: Option<(u32, u32)>,
+    pub variants: Vec<VariantRecord>,
+    pub errors: Vec<FileError>,
+    pub completed: bool,
+    pub up_to_date: bool,
 }
@@ -0,0 +1,94 @@
+use image::RgbaImage;
+use libwebp_sys::{
+    WebPAnimEncoder, WebPAnimEncoderAdd, WebPAnimEncoderAssemble, WebPAnimEncoderDelete,
+    WebPAnimEncoderGetError, WebPAnimEncoderNewInternal, WebPAnimEncoderOptions,
+    WebPAnimEncoderOptionsInitInternal, WebPConfig, WebPData, WebPDataClear, WebPPicture,
+    WebPPictureFree, WebPPictureImportRGBA, WEBP_MUX_ABI_VERSION,
+};
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+// Encodes `frames` (each with its display time in ms) through libwebp's
+// animation encoder. `loop_count` follows the WebP convention: 0 loops forever.
+pub fn encode(
+    frames: &[(RgbaImage, u32)],
+    loop_count: u16,
+    config: &WebPConfig,
+) -> Result<Vec<u8>, String> {
+    let Some((first, _)) = frames.first() else {
+        return Err("animation has no frames".to_string());
+    };
+    let (width, height) = first.dimensions();
+
+    unsafe {
+        let mut options: WebPAnimEncoderOptions = mem::zeroed();
+        if WebPAnimEncoderOptionsInitInternal(&mut options, WEBP_MUX_ABI_VERSION as c_int) == 0 {
+            return Err("libwebp version mismatch".to_string());
+        }
+        options.anim_params.loop_count = c_int::from(loop_count);
+
+        let enc = WebPAnimEncoderNewInternal(
+            width as c_int,
+            height as c_int,
+            &options,
+            WEBP_MUX_ABI_VERSION as c_int,
+        );
+        if enc.is_null() {
+            return Err("failed to initialise the WebP animation encoder".to_string());
+        }
+
+        let result = assemble(enc, frames, config);
+        WebPAnimEncoderDelete(enc);
+        result
+    }
+}
+
+unsafe fn assemble(
+    enc: *mut WebPAnimEncoder,
+    frames: &[(RgbaImage, u32)],
+    config: &WebPConfig,
+) -> Result<Vec<u8>, String> {
+    let mut timestamp: c_int = 0;
+
+    for (frame, delay) in frames {
+        let (width, height) = frame.dimensions();
+        let mut picture = WebPPicture::new().map_err(|_| "libwebp version mismatch")?;
+        picture.use_argb = 1;
+        picture.width = width as c_int;
+        picture.height = height as c_int;
+
+        let added = WebPPictureImportRGBA(&mut picture, frame.as_ptr(), (width * 4) as c_int) != 0
+            && WebPAnimEncoderAdd(enc, &mut picture, timestamp, config) != 0;
+        WebPPictureFree(&mut picture);
+        if !added {
+            return Err(encoder_error(enc));
+        }
+
+        timestamp = timestamp.saturating_add(c_int::try_from(*delay).unwrap_or(c_int::MAX));
+    }
+
+    // The closing null frame sets how long the last real frame stays up.
+    if WebPAnimEncoderAdd(enc, ptr::null_mut(), timestamp, ptr::null()) == 0 {
+        return Err(encoder_error(enc));
+    }
+
+    let mut data = WebPData::default();
+    if WebPAnimEncoderAssemble(enc, &mut data) == 0 {
+        return Err(encoder_error(enc));
+    }
+    let out = slice::from_raw_parts(data.bytes, data.size).to_vec();
+    WebPDataClear(&mut data);
+    Ok(out)
+}
+
+unsafe fn encoder_error(enc: *mut WebPAnimEncoder) -> String {
+    let msg = WebPAnimEncoderGetError(enc);
+    if msg.is_null() {
+        "WebP animation encoding failed".to_string()
+    } else {
+        CStr::from_ptr(msg).to_string_lossy().into_owned()
+    }
+}